@@ -0,0 +1,287 @@
+
+// #[derive(Args)] for args::Args. There's no syn/quote here, so the struct is
+// picked apart straight from the TokenStream and the impl is generated as a
+// string.
+//
+// bool fields become flags, Option<T> fields optional kv args, and anything
+// else a required kv arg. Fields take #[arg(short = 'f', long = "first",
+// help = "...")]; the long key defaults to the field name with '_' -> '-', and
+// the help text to the doc comment. A one-letter field, like `n: u32`, must
+// be given a longer long key, since long keys can't be one char.
+//
+// The impl's command() builds the same Parser as parse_iter(), so e.g. the
+// help text can be printed after ParseError::HelpRequested.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+
+#[proc_macro_derive(Args, attributes(arg))]
+pub fn derive_args(input: TokenStream) -> TokenStream {
+    let out = match expand(input) {
+        Ok(out) => out,
+        Err(msg) => format!("compile_error!({:?});", msg),
+    };
+    out.parse().unwrap()
+}
+
+
+
+
+
+#[derive(Default)]
+struct Attrs {
+    short: Option<String>, // Char literal, as written
+    long: Option<String>, // String literal, as written
+    help: Option<String>, // String literal, as written
+    doc: Option<String>, // String literal of the first doc comment line
+}
+
+enum Kind {
+    Flag,
+    Optional(String), // Inner type of the Option
+    Required(String),
+}
+
+struct Field {
+    ident: String,
+    kind: Kind,
+    attrs: Attrs,
+}
+
+
+fn expand(input: TokenStream) -> Result<String, String> {
+    let mut tokens = input.into_iter();
+
+    // Skip attributes and visibility; they're groups or idents before `struct`
+    let mut name = None;
+    while let Some(tt) = tokens.next() {
+        if let TokenTree::Ident(ident) = tt {
+            match ident.to_string().as_str() {
+                "struct" => {
+                    if let Some(TokenTree::Ident(ident)) = tokens.next() {
+                        name = Some(ident.to_string());
+                    }
+                    break;
+                }
+                "enum" | "union" => return Err(String::from("#[derive(Args)] only supports structs")),
+                _ => {}
+            }
+        }
+    }
+    let name = name.ok_or_else(|| String::from("expected a struct"))?;
+
+    let body = match tokens.next() {
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Brace => g.stream(),
+        Some(TokenTree::Punct(ref p)) if p.as_char() == '<' =>
+            return Err(String::from("#[derive(Args)] doesn't support generic structs")),
+        _ => return Err(String::from("#[derive(Args)] needs a struct with named fields")),
+    };
+
+    let mut registrations = String::new();
+    let mut inits = String::new();
+    for field in parse_fields(body)? {
+        // Long keys must be more than one char, so `n: u32` needs a long
+        let long = match field.attrs.long {
+            Some(long) if long.chars().count() > 3 => long,
+            Some(long) => return Err(format!("#[arg(long = {})] must be more than one character", long)),
+            None if field.ident.chars().count() > 1 => format!("{:?}", field.ident.replace('_', "-")),
+            None => return Err(format!(
+                "single-character field `{}` needs #[arg(long = \"...\")]", field.ident)),
+        };
+        let short = match field.attrs.short {
+            Some(short) => format!("::std::option::Option::Some({})", short),
+            None => String::from("::std::option::Option::None"),
+        };
+        let help = match (field.attrs.help, field.attrs.doc) {
+            (Some(help), _) => help,
+            (None, Some(doc)) => format!("{}.trim()", doc),
+            (None, None) => String::from("\"\""),
+        };
+        let long = format!("::std::string::String::from({})", long);
+        let help = format!("::std::string::String::from({})", help);
+        let handle = format!("__arg_{}", field.ident);
+
+        match field.kind {
+            Kind::Flag => {
+                registrations += &format!(
+                    "let {} = __parser.add_flag_arg(::args::FlagArg::new({}, {}, {}));\n",
+                    handle, long, help, short);
+                inits += &format!("{}: ::args::FlagArgBase::found(__parser.arg({})),\n",
+                    field.ident, handle);
+            }
+            Kind::Optional(ty) => {
+                registrations += &format!(
+                    "let {} = __parser.add_kv_arg(::args::KVArg::<{}>::new({}, {}, {}));\n",
+                    handle, ty, long, short, help);
+                inits += &format!("{}: __parser.arg_mut({}).val(),\n", field.ident, handle);
+            }
+            Kind::Required(ty) => {
+                registrations += &format!(
+                    "let {} = __parser.add_kv_arg({{ \
+                        let mut arg = ::args::KVArg::<{}>::new({}, {}, {}); \
+                        arg.set_required(true); \
+                        arg \
+                    }});\n",
+                    handle, ty, long, short, help);
+                // Required, so parse_iter() fails before this if it's missing
+                inits += &format!("{}: __parser.arg_mut({}).val().unwrap(),\n", field.ident, handle);
+            }
+        }
+    }
+
+    Ok(format!("
+        impl ::args::Args for {name} {{
+            fn command() -> ::args::Parser {{
+                let mut __parser = ::args::Parser::new();
+                {registrations}
+                __parser
+            }}
+
+            fn parse_iter<I, S>(args: I) -> ::std::result::Result<Self, ::args::ParseError>
+                where I: ::std::iter::IntoIterator<Item = S>,
+                    S: ::std::convert::AsRef<::std::ffi::OsStr> {{
+
+                let mut __parser = ::args::Parser::new();
+                {registrations}
+                __parser.parse_iter(args)?;
+                ::std::result::Result::Ok({name}{{
+                    {inits}
+                }})
+            }}
+        }}",
+        name = name, registrations = registrations, inits = inits))
+}
+
+
+fn parse_fields(body: TokenStream) -> Result<Vec<Field>, String> {
+    let mut fields = Vec::new();
+    let mut tokens = body.into_iter().peekable();
+
+    while tokens.peek().is_some() {
+        let mut attrs = Attrs::default();
+        while let Some(TokenTree::Punct(p)) = tokens.peek() {
+            if p.as_char() != '#' { break; }
+            tokens.next();
+            match tokens.next() {
+                Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Bracket =>
+                    parse_attr(g.stream(), &mut attrs)?,
+                _ => return Err(String::from("malformed attribute")),
+            }
+        }
+
+        if let Some(TokenTree::Ident(ident)) = tokens.peek() {
+            if ident.to_string() == "pub" {
+                tokens.next();
+                if let Some(TokenTree::Group(g)) = tokens.peek() {
+                    if g.delimiter() == Delimiter::Parenthesis { tokens.next(); }
+                }
+            }
+        }
+
+        let ident = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ => return Err(String::from("expected a field name")),
+        };
+        match tokens.next() {
+            Some(TokenTree::Punct(ref p)) if p.as_char() == ':' => {}
+            _ => return Err(format!("expected `:` after field `{}`", ident)),
+        }
+
+        // The type runs to the next comma outside of angle brackets. The `>`
+        // of a `->`, as in `Box<dyn Fn() -> T>`, doesn't close one.
+        let mut ty = Vec::new();
+        let mut depth = 0;
+        let mut after_dash = false;
+        for tt in tokens.by_ref() {
+            if let TokenTree::Punct(ref p) = tt {
+                match p.as_char() {
+                    '<' => depth += 1,
+                    '>' if !after_dash => depth -= 1,
+                    ',' if depth == 0 => break,
+                    _ => {}
+                }
+                after_dash = p.as_char() == '-' && p.spacing() == Spacing::Joint;
+            } else {
+                after_dash = false;
+            }
+            ty.push(tt);
+        }
+
+        fields.push(Field{ident, kind: classify(&ty), attrs});
+    }
+
+    Ok(fields)
+}
+
+
+fn classify(ty: &[TokenTree]) -> Kind {
+    let is_ident = |tt: &TokenTree, s: &str| match tt {
+        TokenTree::Ident(ident) => ident.to_string() == s,
+        _ => false,
+    };
+    let is_punct = |tt: &TokenTree, c: char| match tt {
+        TokenTree::Punct(p) => p.as_char() == c,
+        _ => false,
+    };
+    let to_string = |tts: &[TokenTree]| tts.iter().cloned().collect::<TokenStream>().to_string();
+
+    if ty.len() == 1 && is_ident(&ty[0], "bool") {
+        Kind::Flag
+    } else if ty.len() > 3 && is_ident(&ty[0], "Option") && is_punct(&ty[1], '<')
+            && is_punct(&ty[ty.len() - 1], '>') {
+        Kind::Optional(to_string(&ty[2..ty.len() - 1]))
+    } else {
+        Kind::Required(to_string(ty))
+    }
+}
+
+
+fn parse_attr(attr: TokenStream, attrs: &mut Attrs) -> Result<(), String> {
+    let mut tokens = attr.into_iter();
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(ident)) => ident.to_string(),
+        _ => return Ok(()),
+    };
+
+    match name.as_str() {
+        // Doc comments arrive as #[doc = "..."]; only the first line is used
+        "doc" => {
+            if let (Some(TokenTree::Punct(_)), Some(TokenTree::Literal(lit))) = (tokens.next(), tokens.next()) {
+                if attrs.doc.is_none() { attrs.doc = Some(lit.to_string()); }
+            }
+        }
+        "arg" => {
+            let inner: Vec<TokenTree> = match tokens.next() {
+                Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Parenthesis =>
+                    g.stream().into_iter().collect(),
+                _ => return Err(String::from("expected #[arg(...)]")),
+            };
+
+            let is_comma = |tt: &TokenTree| matches!(tt, TokenTree::Punct(p) if p.as_char() == ',');
+            for item in inner.split(is_comma) {
+                let (key, lit) = match item {
+                    [] => continue, // Trailing comma
+                    [TokenTree::Ident(key), TokenTree::Punct(eq), TokenTree::Literal(lit)] if eq.as_char() == '=' =>
+                        (key.to_string(), lit.to_string()),
+                    _ => return Err(String::from("expected `key = value` in #[arg(...)]")),
+                };
+
+                let quote = if key == "short" { '\'' } else { '"' };
+                if !lit.starts_with(quote) {
+                    return Err(format!("#[arg({} = ...)] takes a {} literal", key,
+                        if key == "short" { "char" } else { "string" }));
+                }
+                match key.as_str() {
+                    "short" => attrs.short = Some(lit),
+                    "long" => attrs.long = Some(lit),
+                    "help" => attrs.help = Some(lit),
+                    _ => return Err(format!("unknown #[arg] key `{}`", key)),
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+extern crate args;
+
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use args::{Args, ParseError};
+
+
+#[derive(Args, Debug, PartialEq)]
+struct Opts {
+    #[arg(short = 'f', long = "first", help = "the first number")]
+    first: i32,
+
+    /// be chatty
+    verbose: bool,
+}
+
+#[derive(Args, Debug, PartialEq)]
+struct Optional {
+    output_file: Option<String>,
+}
+
+// Only here so a field's type can contain `->`
+#[derive(Debug, PartialEq)]
+struct Tagged<F>(u8, PhantomData<F>);
+
+impl<F> FromStr for Tagged<F> {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> { Ok(Tagged(s.parse()?, PhantomData)) }
+}
+
+#[derive(Args, Debug, PartialEq)]
+struct Arrow {
+    tag: Option<Tagged<fn() -> u8>>,
+    count: u32,
+}
+
+
+#[test]
+fn two_fields() {
+    let opts = Opts::parse_iter(vec!["prog", "-f", "42", "--verbose"]).unwrap();
+    assert_eq!(opts, Opts{first: 42, verbose: true});
+
+    let opts = Opts::parse_iter(vec!["prog", "--first=7"]).unwrap();
+    assert_eq!(opts, Opts{first: 7, verbose: false});
+}
+
+#[test]
+fn required_field_missing() {
+    assert_eq!(Opts::parse_iter(vec!["prog", "--verbose"]),
+        Err(ParseError::MissingRequired(vec![String::from("first")])));
+}
+
+#[test]
+fn optional_field() {
+    assert_eq!(Optional::parse_iter(vec!["prog"]).unwrap(), Optional{output_file: None});
+    assert_eq!(Optional::parse_iter(vec!["prog", "--output-file", "a.txt"]).unwrap(),
+        Optional{output_file: Some(String::from("a.txt"))});
+}
+
+#[test]
+fn arrow_in_field_type() {
+    assert_eq!(Arrow::parse_iter(vec!["prog", "--tag", "3", "--count", "4"]).unwrap(),
+        Arrow{tag: Some(Tagged(3, PhantomData)), count: 4});
+}
+
+#[test]
+fn help_after_help_requested() {
+    assert_eq!(Opts::parse_iter(vec!["prog", "--help"]), Err(ParseError::HelpRequested));
+    let help = Opts::help();
+    assert!(help.contains("--first, -f"));
+    assert!(help.contains("the first number"));
+    assert!(help.contains("be chatty"));
+
+    // command() takes the same args as parse_iter()
+    let mut parser = Opts::command();
+    parser.parse_iter(vec!["prog", "-f", "1"]).unwrap();
+    assert_eq!(parser.value_of::<i32>("first"), Some(1));
+}
+
+// A one-letter field without a long key is a compile error:
+//
+//     #[derive(Args)]
+//     struct Short { n: u32 }
+//
+// error: single-character field `n` needs #[arg(long = "...")]
+#[derive(Args, Debug, PartialEq)]
+struct OneLetter {
+    #[arg(long = "count")]
+    n: u32,
+}
+
+#[test]
+fn one_letter_field_with_long() {
+    assert_eq!(OneLetter::parse_iter(vec!["prog", "--count", "2"]).unwrap(), OneLetter{n: 2});
+}
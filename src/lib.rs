@@ -1,61 +1,375 @@
 
 use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
-use std::fmt::Debug;
-use std::cell::{RefCell};
+use std::fmt::{self, Debug, Display};
+use std::io::IsTerminal;
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::ops::RangeInclusive;
+#[cfg(feature = "color")]
+use std::sync::atomic::{AtomicU8, Ordering};
 
+#[cfg(feature = "derive")]
+extern crate args_derive;
 
-pub trait PosArgBase {
+// With the "derive" feature, #[derive(Args)] implements Args for a struct
+#[cfg(feature = "derive")]
+pub use args_derive::Args;
+
+
+// Where in argv an error happened isn't part of the error itself; see
+// LocatedError, which parse_vec() and parse_located() return
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnknownKey{key: String, suggestion: Option<String>},
+    TooManyPositional,
+    DuplicateArg(String),
+    MissingValue(String),
+    MissingRequired(Vec<String>),
+    MissingPositional(String),
+    HelpRequested,
+    VersionRequested(String),
+    AmbiguousKey{input: String, candidates: Vec<String>},
+    InvalidUtf8(String),
+    InvalidValue{name: String, value: String, msg: String},
+    InvalidChoice{name: String, value: String, allowed: Vec<String>},
+    Conflict{a: String, b: String}, // Mutually exclusive args given together
+    MissingDependency{arg: String, requires: String},
+    Validation{name: String, msg: String}, // Rejected by a validator
+    Config{path: String, line: Option<usize>, msg: String}, // No line if the file couldn't be read
+    PathNotFound{name: String, path: String},
+    NotAFile{name: String, path: String},
+    NotADirectory{name: String, path: String},
+    OutOfRange{name: String, value: String, min: String, max: String},
+    Multiple(Vec<ParseError>), // With collect_all_errors(), in the order found
+    UnseparatedValue{key: String, kv_key: String}, // E.g. `--outputfile` for `--output file`
+    ResponseFile{path: String, msg: String}, // An `@file` couldn't be read
+    TooManyOccurrences{name: String, max: usize},
+    FlagTakesNoValue(String), // E.g. `--verbose=true`; the key as given
+    WrongValueStyle{key: String, style: ValueStyle}, // The value wasn't given the way style requires
+}
+
+
+impl ParseError {
+    // Display as the parser prints it, with an `error:` prefix (bold red
+    // if color) on each line
+    pub fn paint(&self, color: bool) -> String {
+        if let ParseError::Multiple(errors) = self {
+            return errors.iter().map(|error| error.paint(color)).collect::<Vec<_>>().join("\n");
+        }
+        format!("{} {}", paint("error:", BOLD_RED, color), self)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnknownKey{key, suggestion} => {
+                write!(f, "unknown option `{}`", dashed(key))?;
+                if let Some(suggestion) = suggestion {
+                    write!(f, "; did you mean `{}`?", dashed(suggestion))?;
+                }
+                Ok(())
+            }
+            ParseError::TooManyPositional => write!(f, "too many positional arguments"),
+            ParseError::DuplicateArg(key) => write!(f, "`{}` given more than once", dashed(key)),
+            ParseError::MissingValue(key) => write!(f, "`{}` needs a value", dashed(key)),
+            ParseError::MissingRequired(names) => write!(f, "missing required arguments: {}", names.join(", ")),
+            ParseError::MissingPositional(name) => write!(f, "missing positional argument `<{}>`", name),
+            ParseError::HelpRequested => write!(f, "help requested"),
+            ParseError::VersionRequested(version) => write!(f, "version requested ({})", version),
+            ParseError::AmbiguousKey{input, candidates} => {
+                let candidates: Vec<String> = candidates.iter().map(|c| dashed(c)).collect();
+                write!(f, "`{}` is ambiguous; could be {}", dashed(input), candidates.join(", "))
+            }
+            ParseError::InvalidUtf8(arg) => write!(f, "`{}` is not valid UTF-8", arg),
+            ParseError::InvalidValue{name, value, msg} =>
+                write!(f, "invalid value `{}` for `{}`: {}", value, name, msg),
+            ParseError::InvalidChoice{name, value, allowed} =>
+                write!(f, "invalid value `{}` for `{}`; possible values: {}", value, name, allowed.join(", ")),
+            ParseError::Conflict{a, b} => write!(f, "`{}` can't be used with `{}`", a, b),
+            ParseError::MissingDependency{arg, requires} => write!(f, "`{}` requires `{}`", arg, requires),
+            ParseError::Validation{name, msg} => write!(f, "invalid value for `{}`: {}", name, msg),
+            ParseError::Config{path, line: Some(line), msg} => write!(f, "{}:{}: {}", path, line, msg),
+            ParseError::Config{path, line: None, msg} => write!(f, "{}: {}", path, msg),
+            ParseError::PathNotFound{name, path} => write!(f, "`{}` (for `{}`) doesn't exist", path, name),
+            ParseError::NotAFile{name, path} => write!(f, "`{}` (for `{}`) isn't a file", path, name),
+            ParseError::NotADirectory{name, path} => write!(f, "`{}` (for `{}`) isn't a directory", path, name),
+            ParseError::OutOfRange{name, value, min, max} =>
+                write!(f, "`{}` for `{}` is out of range; must be {} to {}", value, name, min, max),
+            // One line per error
+            ParseError::Multiple(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 { writeln!(f)?; }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            ParseError::ResponseFile{path, msg} => write!(f, "can't read `@{}`: {}", path, msg),
+            ParseError::TooManyOccurrences{name, max} => write!(f, "`{}` may be given at most {} times", name, max),
+            ParseError::FlagTakesNoValue(key) => write!(f, "`{}` takes no value", dashed(key)),
+            ParseError::WrongValueStyle{key, style: ValueStyle::Equals} =>
+                write!(f, "`{}` takes its value in the same token, e.g. `{}=VALUE`", dashed(key), dashed(key)),
+            ParseError::WrongValueStyle{key, ..} =>
+                write!(f, "`{}` takes its value as the next token, e.g. `{} VALUE`", dashed(key), dashed(key)),
+            ParseError::UnseparatedValue{key, kv_key} => {
+                let val = &key[kv_key.len()..];
+                write!(f, "unknown option `--{}`; `--{}` takes a value, did you mean `--{} {}`?", key, kv_key, kv_key, val)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// A ParseError and the index into argv of the token that caused it. index is
+// None for failed post-parse checks like MissingRequired. From a subcommand,
+// it's still an index into the whole argv.
+#[derive(Debug, PartialEq)]
+pub struct LocatedError {
+    pub kind: ParseError,
+    pub index: Option<usize>,
+}
+
+impl fmt::Display for LocatedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::error::Error for LocatedError {}
+
+impl From<LocatedError> for ParseError {
+    fn from(e: LocatedError) -> Self { e.kind }
+}
+
+// inline is whether the value came in the key's token
+fn check_value_style(key: &str, style: ValueStyle, inline: bool) -> Result<(), ParseError> {
+    match (style, inline) {
+        (ValueStyle::Equals, false) | (ValueStyle::Space, true) =>
+            Err(ParseError::WrongValueStyle{key: String::from(key), style}),
+        _ => Ok(()),
+    }
+}
+
+// A key as it's typed, e.g. `--verbose` or `-v`
+fn dashed(key: &str) -> String {
+    if key.chars().count() > 1 {
+        format!("--{}", key)
+    } else {
+        format!("-{}", key)
+    }
+}
+
+
+pub trait PosArgBase: Any {
     fn name(&self) -> &str;
     fn desc(&self) -> &str;
     fn found(&self) -> bool;
-    fn parse(&mut self, s: &str);
+    fn required(&self) -> bool { false }
+    fn hidden(&self) -> bool { false } // Parsed but left out of help()
+    fn long_desc(&self) -> Option<&str> { None } // Only in help_verbose()
+    fn variadic(&self) -> bool { false } // Takes all remaining positionals
+    fn choices(&self) -> Option<&[String]> { None } // Restricts accepted values
+    fn reset(&mut self); // Forgets the parsed value
+    fn parse(&mut self, s: &str) -> Result<(), String>;
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.parse(os_to_str(s)?)
+    }
 }
 
 pub struct PosArg<T> where T: FromStr, <T as FromStr>::Err: Debug {
     name: String,
     desc: String,
     val: Option<T>,
+    required: bool,
+    hidden: bool,
+    long_desc: Option<String>,
 }
 
 impl<T> PosArg<T> 
     where T: FromStr, 
         <T as FromStr>::Err: Debug {
 
-    pub fn new(name: String, desc: String) -> Self {
-        Self{name, desc, val: None}
+    pub fn new(name: impl Into<String>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), val: None, required: false, hidden: false, long_desc: None}
     }
 
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    pub fn set_hidden(&mut self, hidden: bool) { self.hidden = hidden; }
+
+    // Details and examples shown under desc by help_verbose()
+    pub fn set_long_desc(&mut self, long_desc: &str) { self.long_desc = Some(String::from(long_desc)); }
+
+    // Takes the value out; prefer get() to read it without consuming
     pub fn val(&mut self) -> Option<T> { self.val.take() }
+
+    pub fn get(&self) -> Option<&T> { self.val.as_ref() }
 }
 
 impl<T> PosArgBase for PosArg<T> 
-    where T: FromStr, 
+    where T: FromStr + 'static, 
         <T as FromStr>::Err: Debug {
 
     fn name(&self) -> &str { &self.name }
     fn desc(&self) -> &str { &self.desc }
     fn found(&self) -> bool { self.val.is_some() }
+    fn required(&self) -> bool { self.required }
+    fn hidden(&self) -> bool { self.hidden }
+    fn long_desc(&self) -> Option<&str> { self.long_desc.as_deref() }
+
+    fn reset(&mut self) { self.val = None; }
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.val = Some(T::from_str(s).map_err(|e| format!("{:?}", e))?);
+        Ok(())
+    }
+
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.val = Some(from_os_str(s)?);
+        Ok(())
+    }
+}
+
+
+
+
+
+
+// A positional that must be one of choices, e.g. the `up` in `prog up`. See
+// Parser::command_positional().
+pub struct CommandArg {
+    name: String,
+    desc: String,
+    choices: Vec<String>,
+    val: Option<String>,
+}
+
+impl CommandArg {
+    pub fn new(name: impl Into<String>, desc: impl Into<String>, choices: Vec<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), choices, val: None}
+    }
+
+    // Takes the value out; prefer get() to read it without consuming
+    pub fn val(&mut self) -> Option<String> { self.val.take() }
+
+    pub fn get(&self) -> Option<&str> { self.val.as_deref() }
+}
+
+impl PosArgBase for CommandArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn found(&self) -> bool { self.val.is_some() }
+    fn required(&self) -> bool { true }
+    fn choices(&self) -> Option<&[String]> { Some(&self.choices) }
 
-    fn parse(&mut self, s: &str) {
-        self.val = T::from_str(s).ok();
+    fn reset(&mut self) { self.val = None; }
+
+    // The parser checks the value against choices() first
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.val = Some(String::from(s));
+        Ok(())
+    }
+}
+
+
+
+
+
+
+// Collects zero or more positionals left after the fixed ones. Must be the
+// last positional registered.
+pub struct VarPosArg<T> where T: FromStr, <T as FromStr>::Err: Debug {
+    name: String,
+    desc: String,
+    vals: Vec<T>,
+    required: bool,
+    hidden: bool,
+}
+
+impl<T> VarPosArg<T>
+    where T: FromStr,
+        <T as FromStr>::Err: Debug {
+
+    pub fn new(name: impl Into<String>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), vals: Vec::new(), required: false, hidden: false}
     }
+
+    // Requires at least one value
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    pub fn set_hidden(&mut self, hidden: bool) { self.hidden = hidden; }
+
+    pub fn vals(&mut self) -> Vec<T> { std::mem::take(&mut self.vals) }
+
+    pub fn get(&self) -> &[T] { &self.vals }
 }
 
+impl<T> PosArgBase for VarPosArg<T>
+    where T: FromStr + 'static,
+        <T as FromStr>::Err: Debug {
+
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn found(&self) -> bool { !self.vals.is_empty() }
+    fn required(&self) -> bool { self.required }
+    fn hidden(&self) -> bool { self.hidden }
+    fn variadic(&self) -> bool { true }
+
+    fn reset(&mut self) { self.vals.clear(); }
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.vals.push(T::from_str(s).map_err(|e| format!("{:?}", e))?);
+        Ok(())
+    }
+
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.vals.push(from_os_str(s)?);
+        Ok(())
+    }
+}
 
 
 
 
 
-pub trait KVArgBase {
+pub trait KVArgBase: Any {
     fn name(&self) -> &str;
     fn desc(&self) -> &str;
     fn short_key(&self) -> Option<char>; // Not valid for positional argument
+    fn long_key(&self) -> Option<&str> { Some(self.name()) } // None for short-only args
     fn found(&self) -> bool;
+    fn required(&self) -> bool { false }
+    fn multiple(&self) -> bool { false } // May occur more than once
+    fn choices(&self) -> Option<&[String]> { None } // Restricts accepted values
+    fn env(&self) -> Option<&str> { None } // Fallback environment variable
+    fn aliases(&self) -> &[String] { &[] } // Additional long names
+    fn hidden(&self) -> bool { false } // Parsed but left out of help()
+    fn long_desc(&self) -> Option<&str> { None } // Only in help_verbose()
+    fn default_value(&self) -> Option<String> { None } // The default as text, if known
+    fn deprecated(&self) -> Option<&str> { None } // Note for warnings(), e.g. "use --new"
+    fn value_name(&self) -> Option<&str> { None } // Help placeholder; defaults to NAME
+    fn override_mode(&self) -> bool { false } // A repeat replaces the value instead of erroring
+    fn allow_hyphen_values(&self) -> bool { false } // Take the next token as the value even if it's a key
+    fn arity(&self) -> usize { 1 } // Values taken per occurrence; parse() is called for each
+    fn max_occurs(&self) -> Option<usize> { None } // For multiple() args
+    fn value_style(&self) -> ValueStyle { ValueStyle::Either } // Where the value may be given
 
-    fn parse(&mut self, s: &str);
+    fn reset(&mut self); // Forgets the parsed value
+    fn parse(&mut self, s: &str) -> Result<(), String>;
+    fn validate(&self) -> Result<(), ParseError> { Ok(()) } // Run after a successful parse
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.parse(os_to_str(s)?)
+    }
 }
 
+type Validator<T> = Box<dyn Fn(&T) -> Result<(), String>>;
+type RangeCheck<T> = Box<dyn Fn(&str, &T) -> Result<(), ParseError>>; // Given the arg name
+type Setter = Box<dyn Fn(&mut Parser, &mut dyn Any)>; // Given parse_into()'s target
+
 pub struct KVArg<T> 
     where T: FromStr, 
         <T as FromStr>::Err: Debug {
@@ -63,198 +377,5154 @@ pub struct KVArg<T>
     desc: String,
     short_key: Option<char>,
     val: Option<T>,
+    default: Option<T>,
+    required: bool,
+    env: Option<String>,
+    aliases: Vec<String>,
+    hidden: bool,
+    value_name: Option<String>,
+    validator: Option<Validator<T>>,
+    range: Option<RangeCheck<T>>,
+    long_desc: Option<String>,
+    default_str: Option<String>, // From set_shown_default()
+    deprecated: Option<String>,
+    raw: Option<String>, // As given, for raw()
+    override_mode: bool,
+    allow_hyphen_values: bool,
+    value_style: ValueStyle,
+    short_only: bool,
 }
 
 impl<T> KVArg<T> 
     where T: FromStr, 
         <T as FromStr>::Err: Debug {
 
-    pub fn new(name: String, short_key: Option<char>, desc: String) -> RefCell<Self> {
-        RefCell::new(Self{name,  desc, val: None, short_key})
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), val: None, short_key, default: None, required: false, env: None, aliases: Vec::new(),
+            hidden: false, value_name: None, validator: None, range: None, long_desc: None, default_str: None, deprecated: None, raw: None, override_mode: false,
+            allow_hyphen_values: false, value_style: ValueStyle::Either, short_only: false}
     }
 
-    pub fn val(&mut self) -> Option<T> { self.val.take() }
+    // Only `-c`, with no long form; the name is the key itself
+    pub fn short_only(short_key: char, desc: impl Into<String>) -> Self {
+        let mut arg = Self::new(short_key.to_string(), Some(short_key), desc);
+        arg.short_only = true;
+        arg
+    }
+
+    // Fluent alternative to new() + set_*(), e.g.
+    // KVArg::<i32>::builder("count").short('c').default(1).build()
+    pub fn builder(name: &str) -> KVArgBuilder<T> {
+        KVArgBuilder{arg: Self::new(name, None, "")}
+    }
+
+    pub fn with_default(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>, default: T) -> Self {
+        let mut arg = Self::new(name, short_key, desc);
+        arg.set_default(default);
+        arg
+    }
+
+    // Falls back to the environment variable env_var if not given on the
+    // command line
+    pub fn with_env(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>, env_var: &str) -> Self {
+        let mut arg = Self::new(name, short_key, desc);
+        arg.set_env(env_var);
+        arg
+    }
+
+    pub fn set_default(&mut self, default: T) { self.default = Some(default); }
+
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    pub fn set_env(&mut self, env_var: &str) { self.env = Some(String::from(env_var)); }
+
+    // Additional long name, e.g. `colour` for `color`
+    pub fn add_alias(&mut self, alias: &str) { self.aliases.push(String::from(alias)); }
+
+    pub fn set_hidden(&mut self, hidden: bool) { self.hidden = hidden; }
+
+    // Placeholder shown in help, e.g. `--output <FILE>`
+    pub fn set_value_name(&mut self, value_name: &str) { self.value_name = Some(String::from(value_name)); }
+
+    // Last one wins: `--out a --out b` gives `b` rather than DuplicateArg
+    pub fn set_override_mode(&mut self, override_mode: bool) { self.override_mode = override_mode; }
+
+    // By default `--pattern -x` is missing its value if `-x` is one of our keys;
+    // with this, `-x` is the pattern
+    pub fn set_allow_hyphen_values(&mut self, allow: bool) { self.allow_hyphen_values = allow; }
+
+    // Only `--jobs=4` (or `-j4`), only `--jobs 4`, or either
+    pub fn set_value_style(&mut self, style: ValueStyle) { self.value_style = style; }
+
+    // Details and examples shown under desc by help_verbose()
+    pub fn set_long_desc(&mut self, long_desc: &str) { self.long_desc = Some(String::from(long_desc)); }
+
+    // Still parsed, but using it adds a warning with note, e.g. "use --new"
+    pub fn set_deprecated(&mut self, note: &str) { self.deprecated = Some(String::from(note)); }
+
+    // Checks a parsed value, e.g. that a port is in 1..=65535
+    pub fn set_validator<F>(&mut self, validator: F) where F: Fn(&T) -> Result<(), String> + 'static {
+        self.validator = Some(Box::new(validator));
+    }
+
+    // Falls back to the default if no value was parsed; use found() to tell
+    // the two apart. Takes the value out; prefer get() to read it without
+    // consuming.
+    pub fn val(&mut self) -> Option<T> { self.val.take().or_else(|| self.default.take()) }
+
+    pub fn get(&self) -> Option<&T> { self.val.as_ref().or(self.default.as_ref()) }
+
+    // The value as given, before parsing; None for the default. Non-UTF-8 is
+    // replaced lossily.
+    pub fn raw(&self) -> Option<&str> { self.raw.as_deref() }
+}
+
+
+impl<T> KVArg<T>
+    where T: FromStr + Display,
+        <T as FromStr>::Err: Debug {
+
+    // Like set_default(), but the default is also shown in help() and given
+    // by default_value(). It's written with Display, so a String default has
+    // no quotes.
+    pub fn set_shown_default(&mut self, default: T) {
+        self.default_str = Some(default.to_string());
+        self.default = Some(default);
+    }
+}
+
+
+impl<T> KVArg<T>
+    where T: FromStr + PartialOrd + Display + 'static,
+        <T as FromStr>::Err: Debug {
+
+    // Rejects values outside of range with ParseError::OutOfRange
+    pub fn range(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>, range: RangeInclusive<T>) -> Self {
+        let mut arg = Self::new(name, short_key, desc);
+        arg.range = Some(Box::new(move |name, val| {
+            if range.contains(val) {
+                return Ok(());
+            }
+            Err(ParseError::OutOfRange{
+                name: String::from(name),
+                value: val.to_string(),
+                min: range.start().to_string(),
+                max: range.end().to_string(),
+            })
+        }));
+        arg
+    }
+}
+
+
+pub struct KVArgBuilder<T>
+    where T: FromStr,
+        <T as FromStr>::Err: Debug {
+    arg: KVArg<T>,
+}
+
+impl<T> KVArgBuilder<T>
+    where T: FromStr,
+        <T as FromStr>::Err: Debug {
+
+    pub fn short(mut self, short_key: char) -> Self { self.arg.short_key = Some(short_key); self }
+
+    pub fn desc(mut self, desc: &str) -> Self { self.arg.desc = String::from(desc); self }
+
+    pub fn default(mut self, default: T) -> Self { self.arg.set_default(default); self }
+
+    pub fn required(mut self, required: bool) -> Self { self.arg.set_required(required); self }
+
+    pub fn env(mut self, env_var: &str) -> Self { self.arg.set_env(env_var); self }
+
+    pub fn alias(mut self, alias: &str) -> Self { self.arg.add_alias(alias); self }
+
+    pub fn hidden(mut self, hidden: bool) -> Self { self.arg.set_hidden(hidden); self }
+
+    pub fn long_desc(mut self, long_desc: &str) -> Self { self.arg.set_long_desc(long_desc); self }
+
+    pub fn deprecated(mut self, note: &str) -> Self { self.arg.set_deprecated(note); self }
+
+    pub fn value_name(mut self, value_name: &str) -> Self { self.arg.set_value_name(value_name); self }
+
+    pub fn override_mode(mut self, override_mode: bool) -> Self { self.arg.set_override_mode(override_mode); self }
+
+    pub fn allow_hyphen_values(mut self, allow: bool) -> Self { self.arg.set_allow_hyphen_values(allow); self }
+
+    pub fn value_style(mut self, style: ValueStyle) -> Self { self.arg.set_value_style(style); self }
+
+    pub fn validator<F>(mut self, validator: F) -> Self where F: Fn(&T) -> Result<(), String> + 'static {
+        self.arg.set_validator(validator);
+        self
+    }
+
+    pub fn build(self) -> KVArg<T> { self.arg }
+}
+
+impl<T> KVArgBuilder<T>
+    where T: FromStr + Display,
+        <T as FromStr>::Err: Debug {
+
+    pub fn shown_default(mut self, default: T) -> Self { self.arg.set_shown_default(default); self }
 }
 
 
-impl<T> KVArgBase for KVArg<T> where T: FromStr, <T as FromStr>::Err: Debug {
+impl<T> KVArgBase for KVArg<T> where T: FromStr + 'static, <T as FromStr>::Err: Debug {
     fn name(&self) -> &str { &self.name }
     fn desc(&self) -> &str { &self.desc }
     fn short_key(&self) -> Option<char> { self.short_key }
+    fn long_key(&self) -> Option<&str> { if self.short_only { None } else { Some(&self.name) } }
     fn found(&self) -> bool { self.val.is_some() }
+    fn required(&self) -> bool { self.required }
+    fn env(&self) -> Option<&str> { self.env.as_deref() }
+    fn aliases(&self) -> &[String] { &self.aliases }
+    fn hidden(&self) -> bool { self.hidden }
+    fn long_desc(&self) -> Option<&str> { self.long_desc.as_deref() }
+    fn default_value(&self) -> Option<String> { self.default_str.clone() }
+    fn deprecated(&self) -> Option<&str> { self.deprecated.as_deref() }
+    fn value_name(&self) -> Option<&str> { self.value_name.as_deref() }
+    fn override_mode(&self) -> bool { self.override_mode }
+    fn allow_hyphen_values(&self) -> bool { self.allow_hyphen_values }
+    fn value_style(&self) -> ValueStyle { self.value_style }
 
-    fn parse(&mut self, s: &str) {
-        self.val = T::from_str(s).ok();
+    fn reset(&mut self) {
+        self.val = None;
+        self.raw = None;
+    }
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.val = Some(T::from_str(s).map_err(|e| format!("{:?}", e))?);
+        self.raw = Some(String::from(s));
+        Ok(())
     }
 
-}
-
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.val = Some(from_os_str(s)?);
+        self.raw = Some(s.to_string_lossy().into_owned());
+        Ok(())
+    }
 
+    fn validate(&self) -> Result<(), ParseError> {
+        if let (Some(range), Some(val)) = (&self.range, &self.val) {
+            range(&self.name, val)?;
+        }
+        match (&self.validator, &self.val) {
+            (Some(validator), Some(val)) => validator(val)
+                .map_err(|msg| ParseError::Validation{name: self.name.clone(), msg}),
+            _ => Ok(()),
+        }
+    }
 
+}
 
 
-pub trait FlagArgBase {
-    fn name(&self) -> &str;
-    fn desc(&self) -> &str;
-    fn short_key(&self) -> Option<char>; // Not valid for positional argument
-    fn found(&self) -> bool;
 
-    fn parse(&mut self);
-}
 
 
-pub struct FlagArg {
+pub struct MultiKVArg<T> 
+    where T: FromStr, 
+        <T as FromStr>::Err: Debug {
     name: String,
     desc: String,
     short_key: Option<char>,
-    val: bool,
+    vals: Vec<T>,
+    required: bool,
+    delimiter: Option<char>,
+    max_occurs: Option<usize>,
 }
 
-impl FlagArg {
-    pub fn new(name: String, desc: String, short_key: Option<char>) -> RefCell<Self> {
-        RefCell::new(Self{name,  desc, short_key, val: false})
+impl<T> MultiKVArg<T> 
+    where T: FromStr, 
+        <T as FromStr>::Err: Debug {
+
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, vals: Vec::new(), required: false, delimiter: None, max_occurs: None}
     }
+
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    // Giving it more than max times is ParseError::TooManyOccurrences. With a
+    // delimiter, `--features a,b` is one occurrence.
+    pub fn set_max_occurs(&mut self, max: usize) { self.max_occurs = Some(max); }
+
+    // Splits each occurrence into several values, e.g. `--features a,b,c`. A
+    // backslash before the delimiter keeps it, so `a\,b,c` is `a,b` and `c`,
+    // and `\\` is one backslash, so `C:\dir\\,d` is `C:\dir\` and `d`. Any
+    // other backslash is left alone.
+    pub fn set_delimiter(&mut self, delimiter: char) { self.delimiter = Some(delimiter); }
+
+    pub fn vals(&mut self) -> Vec<T> { std::mem::take(&mut self.vals) }
 }
 
-impl FlagArgBase for FlagArg {
+
+impl<T> KVArgBase for MultiKVArg<T> where T: FromStr + 'static, <T as FromStr>::Err: Debug {
     fn name(&self) -> &str { &self.name }
     fn desc(&self) -> &str { &self.desc }
     fn short_key(&self) -> Option<char> { self.short_key }
-    fn found(&self) -> bool { self.val }
+    fn found(&self) -> bool { !self.vals.is_empty() }
+    fn required(&self) -> bool { self.required }
+    fn multiple(&self) -> bool { true }
+    fn max_occurs(&self) -> Option<usize> { self.max_occurs }
 
-    fn parse(&mut self) { self.val = true; }
-}
+    fn reset(&mut self) { self.vals.clear(); }
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        let pieces: Vec<String> = match self.delimiter {
+            Some(delimiter) => split_escaped(s, delimiter),
+            None => vec![String::from(s)],
+        };
+        for piece in pieces {
+            self.vals.push(T::from_str(&piece).map_err(|e| format!("{:?}", e))?);
+        }
+        Ok(())
+    }
 
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        if self.delimiter.is_some() {
+            return self.parse(os_to_str(s)?);
+        }
+        self.vals.push(from_os_str(s)?);
+        Ok(())
+    }
 
+}
 
 
 
-pub struct Parser<'a> {
-    pos_args: Vec<&'a mut PosArgBase>,
-    pos_arg_names: HashSet<String>,
 
-    kv_keys: BTreeMap<String, &'a RefCell<KVArgBase>>,
 
-    flag_keys: BTreeMap<String, &'a RefCell<FlagArgBase>>,
+pub struct ChoiceArg {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    choices: Vec<String>,
+    val: Option<String>,
+    required: bool,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new() -> Self { 
-        Self{
-            pos_args: Vec::new(),
-            pos_arg_names: HashSet::new(),
-            kv_keys: BTreeMap::new(),
-            flag_keys: BTreeMap::new(),
-        } 
+impl ChoiceArg {
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>, choices: Vec<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, choices, val: None, required: false}
     }
 
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
 
-    pub fn add_pos_arg(&mut self, pos_arg: &'a mut PosArgBase) {
-        assert!(!self.pos_arg_names.contains(pos_arg.name()));
-        self.pos_arg_names.insert(String::from(pos_arg.name()));
-        self.pos_args.push(pos_arg);
-    }
+    // Takes the value out; prefer get() to read it without consuming
+    pub fn val(&mut self) -> Option<String> { self.val.take() }
 
-    pub fn add_kv_arg(&mut self, kv_arg: &'a RefCell<KVArgBase>) {
+    pub fn get(&self) -> Option<&str> { self.val.as_deref() }
+}
 
-        assert!(!self.kv_keys.contains_key(kv_arg.borrow().name())
-            && !self.flag_keys.contains_key(kv_arg.borrow().name()));
-        assert!(kv_arg.borrow().name().len() > 1);
+impl KVArgBase for ChoiceArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { self.val.is_some() }
+    fn required(&self) -> bool { self.required }
+    fn choices(&self) -> Option<&[String]> { Some(&self.choices) }
 
-        self.kv_keys.insert(String::from(kv_arg.borrow().name()), kv_arg);
+    fn reset(&mut self) { self.val = None; }
 
-        let short_key = kv_arg.borrow().short_key();
-        if let Some(c) = short_key {
-            let cs = c.to_string();
-            assert!(!self.kv_keys.contains_key(&cs) 
-                && !self.flag_keys.contains_key(&cs));
-            self.kv_keys.insert(cs, kv_arg);
-        };
+    // The parser checks the value against choices() first
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.val = Some(String::from(s));
+        Ok(())
     }
+}
 
-    pub fn add_flag_arg(&mut self, flag_arg: &'a RefCell<FlagArgBase>) {
-        assert!(!self.flag_keys.contains_key(flag_arg.borrow().name())
-            && !self.kv_keys.contains_key(flag_arg.borrow().name()));
-        assert!(flag_arg.borrow().name().len() > 1);
 
-        self.flag_keys.insert(String::from(flag_arg.borrow().name()), flag_arg);
 
-        let short_key = flag_arg.borrow().short_key();
-        if let Some(c) = short_key {
-            let cs = c.to_string();
-            assert!(!self.flag_keys.contains_key(&cs)
-                && !self.kv_keys.contains_key(&cs));
-            self.flag_keys.insert(cs, flag_arg);
-        };
-    }
 
-    pub fn parse(&mut self) {
-        self.parse_vec(std::env::args().collect());
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathCheck {
+    Exists,
+    File,
+    Dir,
+}
+
+// A kv arg taking a path, optionally checked against the filesystem at parse
+// time
+pub struct PathArg {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    val: Option<PathBuf>,
+    required: bool,
+    check: Option<PathCheck>,
+}
+
+impl PathArg {
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, val: None, required: false, check: None}
     }
 
-    pub fn parse_vec(&mut self, argv: Vec<String>) {
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
 
-        let mut pos_args_consumed = 0;
+    pub fn set_check(&mut self, check: PathCheck) { self.check = Some(check); }
 
-        let mut it = argv.iter();
-        it.next(); // skip first arg (program path)
-        while let Some(arg) = it.next() {
-            let mut chars = arg.chars();
-            let first = chars.next().unwrap();
-            let second = chars.next().unwrap();
-            
-            if first == '-' {
-                // Long key kv arg
-                let key = if second == '-' {
-                    String::from(&arg[2..])
-                } else {
-                    String::from(&arg[1..])
-                };
+    // Takes the value out; prefer get() to read it without consuming
+    pub fn val(&mut self) -> Option<PathBuf> { self.val.take() }
 
-                if let Some(arg_rc) = self.kv_keys.get(&key) {
-                    assert!(!arg_rc.borrow().found());
-                    // let mut q:() = arg_rc;
-                    arg_rc.borrow_mut().parse(it.next().unwrap());
-                } else if let Some(arg_rc) = self.flag_keys.get(&key) {
-                    assert!(!arg_rc.borrow().found());
-                    arg_rc.borrow_mut().parse();
-                } else {
-                    panic!("No such key `{:?}`", key);
-                }
+    pub fn get(&self) -> Option<&Path> { self.val.as_deref() }
+}
 
-            } else {
-                // Positional arg
-                if pos_args_consumed >= self.pos_args.len() {
-                    panic!("Too many positional args!");
-                }
+impl KVArgBase for PathArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { self.val.is_some() }
+    fn required(&self) -> bool { self.required }
 
-                self.pos_args[pos_args_consumed].parse(arg);
-                pos_args_consumed += 1;
-            }
-        }
+    fn reset(&mut self) { self.val = None; }
+
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.val = Some(PathBuf::from(s));
+        Ok(())
     }
 
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.val = Some(PathBuf::from(s));
+        Ok(())
+    }
 
+    fn validate(&self) -> Result<(), ParseError> {
+        let (path, check) = match (&self.val, self.check) {
+            (Some(path), Some(check)) => (path, check),
+            _ => return Ok(()),
+        };
 
+        let name = self.name.clone();
+        let path_str = path.display().to_string();
+        if !path.exists() {
+            return Err(ParseError::PathNotFound{name, path: path_str});
+        }
+        match check {
+            PathCheck::File if !path.is_file() => Err(ParseError::NotAFile{name, path: path_str}),
+            PathCheck::Dir if !path.is_dir() => Err(ParseError::NotADirectory{name, path: path_str}),
+            _ => Ok(()),
+        }
+    }
 }
 
 
 
 
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn it_works() {
-        let kv = KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string());
+// A kv arg taking a duration like `30s`, `1500ms`, `5m` or `1.5h`
+pub struct DurationArg {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    val: Option<Duration>,
+    default: Option<Duration>,
+    required: bool,
+}
+
+impl DurationArg {
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, val: None, default: None, required: false}
+    }
+
+    pub fn set_default(&mut self, default: Duration) { self.default = Some(default); }
+
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    // Falls back to the default if no value was parsed
+    pub fn get(&self) -> Option<Duration> { self.val.or(self.default) }
+}
+
+impl KVArgBase for DurationArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { self.val.is_some() }
+    fn required(&self) -> bool { self.required }
+
+    fn reset(&mut self) { self.val = None; }
+
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.val = Some(parse_duration(s)?);
+        Ok(())
+    }
+}
+
+
+
+
+
+// Takes exactly arity values per occurrence, e.g. `--point 3 4`
+pub struct FixedArityArg<T> where T: FromStr, <T as FromStr>::Err: Debug {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    arity: usize,
+    vals: Vec<T>,
+    required: bool,
+}
+
+impl<T> FixedArityArg<T>
+    where T: FromStr,
+        <T as FromStr>::Err: Debug {
+
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>, arity: usize) -> Self {
+        assert!(arity > 0);
+        Self{name: name.into(), desc: desc.into(), short_key, arity, vals: Vec::new(), required: false}
+    }
+
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    pub fn vals(&mut self) -> Vec<T> { std::mem::take(&mut self.vals) }
+
+    pub fn get(&self) -> &[T] { &self.vals }
+}
+
+impl<T> KVArgBase for FixedArityArg<T> where T: FromStr + 'static, <T as FromStr>::Err: Debug {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { !self.vals.is_empty() }
+    fn required(&self) -> bool { self.required }
+    fn arity(&self) -> usize { self.arity }
+
+    fn reset(&mut self) { self.vals.clear(); }
+
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        self.vals.push(T::from_str(s).map_err(|e| format!("{:?}", e))?);
+        Ok(())
+    }
+
+    fn parse_os(&mut self, s: &OsStr) -> Result<(), String> {
+        self.vals.push(from_os_str(s)?);
+        Ok(())
+    }
+}
+
+
+
+
+
+// Collects repeated `--define name=value` into a map; a repeated name keeps
+// the last value
+pub struct MapArg {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    map: BTreeMap<String, String>,
+    required: bool,
+}
+
+impl MapArg {
+    pub fn new(name: impl Into<String>, short_key: Option<char>, desc: impl Into<String>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, map: BTreeMap::new(), required: false}
+    }
+
+    pub fn set_required(&mut self, required: bool) { self.required = required; }
+
+    pub fn map(&self) -> &BTreeMap<String, String> { &self.map }
+}
+
+impl KVArgBase for MapArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { !self.map.is_empty() }
+    fn required(&self) -> bool { self.required }
+    fn multiple(&self) -> bool { true }
+    fn value_name(&self) -> Option<&str> { Some("KEY=VALUE") }
+
+    fn reset(&mut self) { self.map.clear(); }
+
+    fn parse(&mut self, s: &str) -> Result<(), String> {
+        let (key, val) = s.split_once('=').ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))?;
+        self.map.insert(String::from(key), String::from(val));
+        Ok(())
+    }
+}
+
+
+
+
+
+pub trait FlagArgBase: Any {
+    fn name(&self) -> &str;
+    fn desc(&self) -> &str;
+    fn short_key(&self) -> Option<char>; // Not valid for positional argument
+    fn long_key(&self) -> Option<&str> { Some(self.name()) } // None for short-only args
+    fn found(&self) -> bool;
+    fn aliases(&self) -> &[String] { &[] } // Additional long names
+    fn negatable(&self) -> bool { false } // Also accepts `--no-<name>`
+    fn hidden(&self) -> bool { false } // Parsed but left out of help()
+    fn long_desc(&self) -> Option<&str> { None } // Only in help_verbose()
+    fn optional_value(&self) -> bool { false } // Also accepts `--<name>=value`
+    fn deprecated(&self) -> Option<&str> { None } // Note for warnings(), e.g. "use --new"
+
+    fn reset(&mut self); // Forgets the parsed value
+    fn parse(&mut self);
+    fn parse_negated(&mut self) {}
+    fn parse_value(&mut self, _s: &str) -> Result<(), String> { Err(String::from("takes no value")) }
+}
+
+
+pub struct FlagArg {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    val: bool,
+    aliases: Vec<String>,
+    hidden: bool,
+    long_desc: Option<String>,
+    deprecated: Option<String>,
+    short_only: bool,
+}
+
+impl FlagArg {
+    pub fn new(name: impl Into<String>, desc: impl Into<String>, short_key: Option<char>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, val: false, aliases: Vec::new(), hidden: false, long_desc: None, deprecated: None,
+            short_only: false}
+    }
+
+    // Only `-x`, with no long form; the name is the key itself
+    pub fn short_only(short_key: char, desc: impl Into<String>) -> Self {
+        Self{short_only: true, ..Self::new(short_key.to_string(), desc, Some(short_key))}
+    }
+
+    // Additional long name, e.g. `colour` for `color`
+    pub fn add_alias(&mut self, alias: &str) { self.aliases.push(String::from(alias)); }
+
+    pub fn set_hidden(&mut self, hidden: bool) { self.hidden = hidden; }
+
+    // Details and examples shown under desc by help_verbose()
+    pub fn set_long_desc(&mut self, long_desc: &str) { self.long_desc = Some(String::from(long_desc)); }
+
+    // Still parsed, but using it adds a warning with note, e.g. "use --new"
+    pub fn set_deprecated(&mut self, note: &str) { self.deprecated = Some(String::from(note)); }
+}
+
+impl FlagArgBase for FlagArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn long_key(&self) -> Option<&str> { if self.short_only { None } else { Some(&self.name) } }
+    fn found(&self) -> bool { self.val }
+    fn aliases(&self) -> &[String] { &self.aliases }
+    fn hidden(&self) -> bool { self.hidden }
+    fn long_desc(&self) -> Option<&str> { self.long_desc.as_deref() }
+    fn deprecated(&self) -> Option<&str> { self.deprecated.as_deref() }
+
+    fn reset(&mut self) { self.val = false; }
+    fn parse(&mut self) { self.val = true; }
+}
+
+
+
+
+
+// A flag that can be explicitly turned off with `--no-<name>`, so unset can
+// be told apart from off
+pub struct NegatableFlag {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    val: Option<bool>,
+}
+
+impl NegatableFlag {
+    pub fn new(name: impl Into<String>, desc: impl Into<String>, short_key: Option<char>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, val: None}
+    }
+
+    pub fn get(&self) -> Option<bool> { self.val }
+}
+
+impl FlagArgBase for NegatableFlag {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { self.val.is_some() }
+    fn negatable(&self) -> bool { true }
+
+    fn reset(&mut self) { self.val = None; }
+    fn parse(&mut self) { self.val = Some(true); }
+    fn parse_negated(&mut self) { self.val = Some(false); }
+}
+
+// `--feature` gives Some(true), `--no-feature` Some(false), and neither None
+pub type TriStateFlag = NegatableFlag;
+
+
+
+
+
+// A flag that may carry an attached value, like `--color` or
+// `--color=always`. The next token is never taken as its value.
+pub struct OptionalValueFlag {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    found: bool,
+    value: Option<String>,
+}
+
+impl OptionalValueFlag {
+    pub fn new(name: impl Into<String>, desc: impl Into<String>, short_key: Option<char>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, found: false, value: None}
+    }
+
+    pub fn value(&self) -> Option<&str> { self.value.as_deref() }
+}
+
+impl FlagArgBase for OptionalValueFlag {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { self.found }
+    fn optional_value(&self) -> bool { true }
+
+    fn reset(&mut self) {
+        self.found = false;
+        self.value = None;
+    }
+    fn parse(&mut self) { self.found = true; }
+    fn parse_value(&mut self, s: &str) -> Result<(), String> {
+        self.found = true;
+        self.value = Some(String::from(s));
+        Ok(())
+    }
+}
+
+
+
+
+
+pub trait CountArgBase: Any {
+    fn name(&self) -> &str;
+    fn desc(&self) -> &str;
+    fn short_key(&self) -> Option<char>;
+    fn found(&self) -> bool;
+    fn max_occurs(&self) -> Option<usize> { None }
+
+    fn reset(&mut self); // Forgets the parsed count
+    fn parse(&mut self);
+}
+
+
+pub struct CountArg {
+    name: String,
+    desc: String,
+    short_key: Option<char>,
+    count: u32,
+    max_occurs: Option<usize>,
+}
+
+impl CountArg {
+    pub fn new(name: impl Into<String>, desc: impl Into<String>, short_key: Option<char>) -> Self {
+        Self{name: name.into(), desc: desc.into(), short_key, count: 0, max_occurs: None}
+    }
+
+    // More than max, e.g. `-vvvv` for 3, is ParseError::TooManyOccurrences
+    pub fn set_max_occurs(&mut self, max: usize) { self.max_occurs = Some(max); }
+
+    pub fn count(&self) -> u32 { self.count }
+}
+
+impl CountArgBase for CountArg {
+    fn name(&self) -> &str { &self.name }
+    fn desc(&self) -> &str { &self.desc }
+    fn short_key(&self) -> Option<char> { self.short_key }
+    fn found(&self) -> bool { self.count > 0 }
+    fn max_occurs(&self) -> Option<usize> { self.max_occurs }
+
+    fn reset(&mut self) { self.count = 0; }
+    fn parse(&mut self) { self.count += 1; }
+}
+
+
+
+
+
+// Integer value that also accepts `0x`, `0o` and `0b` prefixes, e.g.
+// KVArg::<RadixInt<i64>> takes `0x1F`. Plain decimal still works.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RadixInt<T>(pub T);
+
+macro_rules! impl_radix_int {
+    ($($t:ty),*) => {$(
+        impl FromStr for RadixInt<$t> {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let (sign, digits) = match s.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", s.strip_prefix('+').unwrap_or(s)),
+                };
+                let (radix, digits) = match digits.get(..2) {
+                    Some("0x") | Some("0X") => (16, &digits[2..]),
+                    Some("0o") | Some("0O") => (8, &digits[2..]),
+                    Some("0b") | Some("0B") => (2, &digits[2..]),
+                    _ => (10, digits),
+                };
+                // The sign only goes before the prefix; `0x-5` and `--5` are
+                // rejected. ParseIntError can't be built directly, so this
+                // parses something that fails the same way.
+                if digits.starts_with(['+', '-']) {
+                    return "+-".parse::<$t>().map(RadixInt);
+                }
+                <$t>::from_str_radix(&format!("{}{}", sign, digits), radix).map(RadixInt)
+            }
+        }
+    )*};
+}
+
+impl_radix_int!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+
+
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    Pos,
+    KV,
+    Flag,
+    Count,
+}
+
+// A user enum naming subcommands, for Parser::add_subcommand_kind() and
+// subcommand_kind()
+pub trait SubcommandKind: Sized {
+    fn name(&self) -> &str;
+    fn from_name(name: &str) -> Option<Self>;
+}
+
+// Returned by Parser::add_group()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Group {
+    index: usize,
+}
+
+// One arg, as listed by Parser::describe()
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArgSpec {
+    pub name: String,
+    pub short_key: Option<char>,
+    pub kind: ArgKind,
+    pub default: Option<String>,
+    pub required: bool,
+    pub desc: String,
+}
+
+// Returned when an arg is registered, and used to get it back from the Parser
+// after parsing
+pub struct Handle<A> {
+    kind: ArgKind,
+    index: usize,
+    _marker: PhantomData<fn() -> A>,
+}
+
+impl<A> Handle<A> {
+    fn new(kind: ArgKind, index: usize) -> Self {
+        Self{kind, index, _marker: PhantomData}
+    }
+}
+
+impl<A> Clone for Handle<A> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<A> Copy for Handle<A> {}
+
+
+
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+}
+
+
+// Where a kv arg's value may be given. Equals means in the same token, so
+// `--jobs=4`, `-j=4` and `-j4`; Space means in the next, so `--jobs 4` and
+// `-j 4`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ValueStyle {
+    #[default]
+    Either,
+    Equals,
+    Space,
+}
+
+
+// How help() orders the options
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Alphabetical, // By long name
+    Insertion, // As registered
+}
+
+
+
+
+// An immutable, Send + Sync snapshot of a parse, from Parser::into_matches()
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Matches {
+    present: HashSet<String>,
+    values: BTreeMap<String, Vec<String>>, // As given; defaults aren't included
+    trailing: Vec<String>,
+    subcommand: Option<(String, Box<Matches>)>,
+}
+
+impl Matches {
+    // Whether the arg with long name `name`, of any kind, was given
+    pub fn is_present(&self, name: &str) -> bool { self.present.contains(name) }
+
+    // Like Parser::value_of()
+    pub fn value_of<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.values.get(name)?.last()?.parse().ok()
+    }
+
+    // Every value given for a kv or positional arg, in order
+    pub fn values_of(&self, name: &str) -> &[String] {
+        self.values.get(name).map_or(&[], |vals| &vals[..])
+    }
+
+    pub fn trailing(&self) -> &[String] { &self.trailing }
+
+    // The subcommand given, if any, and its matches
+    pub fn subcommand(&self) -> Option<(&str, &Matches)> {
+        self.subcommand.as_ref().map(|(name, matches)| (name.as_str(), &**matches))
+    }
+}
+
+
+
+
+pub struct Parser {
+    pos_args: Vec<Box<dyn PosArgBase>>,
+    pos_arg_names: HashSet<String>,
+
+    kv_args: Vec<Box<dyn KVArgBase>>,
+    kv_keys: BTreeMap<String, usize>,
+
+    flag_args: Vec<Box<dyn FlagArgBase>>,
+    flag_keys: BTreeMap<String, usize>,
+    negated_flag_keys: BTreeMap<String, usize>,
+
+    count_args: Vec<Box<dyn CountArgBase>>,
+    count_keys: BTreeMap<String, usize>,
+
+    allow_negative_numbers: bool,
+    allow_abbreviations: bool,
+    collect_all_errors: bool,
+    ignore_unknown: bool,
+    allow_leftover: bool,
+    collect_extras: bool,
+    partial: bool, // In parse_partial(); extras go to unknown_args, to keep their order
+    response_files: bool,
+    prefix_chars: String,
+    case_insensitive: bool,
+    version: Option<String>,
+    name: Option<String>,
+    about: Option<String>,
+    help_order: Order,
+    groups: Vec<String>, // Help sections, by Group index
+    grouped: BTreeMap<String, usize>, // Arg name to Group index
+    option_names: Vec<String>, // Flag, kv and count arg names in registration order
+
+    subcommands: BTreeMap<String, Parser>,
+    matched_subcommand: Option<String>,
+    default_subcommand: Option<String>,
+
+    trailing: Vec<String>,
+    unknown_args: Vec<String>, // With ignore_unknown()
+    leftover: Vec<OsString>, // With allow_leftover()
+    extras: Vec<String>, // With collect_extras()
+    warnings: Vec<String>,
+    consumed: usize,
+
+    exclusive_groups: Vec<Vec<String>>,
+    requires: Vec<(String, String)>, // (arg, required when arg is given)
+    required_unless: Vec<(String, Vec<String>)>, // (arg, alternatives)
+
+    // From load_defaults(), applied to whatever the command line didn't set
+    config_kvs: Vec<(usize, String)>,
+    config_flags: Vec<usize>,
+
+    error_index: Option<usize>,
+    errors: Vec<ParseError>, // Set aside by collect_all_errors()
+
+    global_keys: HashSet<String>,
+
+    // Every kv and positional value parsed, by arg name, for value_of()
+    raw_values: BTreeMap<String, Vec<String>>,
+
+    // Times given, by arg name, for args with a max_occurs()
+    occurrences: BTreeMap<String, usize>,
+
+    setters: Vec<Setter>, // From the add_*_arg_into() methods
+}
+
+impl Default for Parser {
+    fn default() -> Self { Self::new() }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self{
+            pos_args: Vec::new(),
+            pos_arg_names: HashSet::new(),
+            kv_args: Vec::new(),
+            kv_keys: BTreeMap::new(),
+            flag_args: Vec::new(),
+            flag_keys: BTreeMap::new(),
+            negated_flag_keys: BTreeMap::new(),
+            count_args: Vec::new(),
+            count_keys: BTreeMap::new(),
+            allow_negative_numbers: false,
+            allow_abbreviations: false,
+            collect_all_errors: false,
+            ignore_unknown: false,
+            allow_leftover: false,
+            collect_extras: false,
+            partial: false,
+            response_files: false,
+            prefix_chars: String::from("-"),
+            case_insensitive: false,
+            version: None,
+            name: None,
+            about: None,
+            help_order: Order::Alphabetical,
+            groups: Vec::new(),
+            grouped: BTreeMap::new(),
+            option_names: Vec::new(),
+            subcommands: BTreeMap::new(),
+            matched_subcommand: None,
+            default_subcommand: None,
+            trailing: Vec::new(),
+            unknown_args: Vec::new(),
+            leftover: Vec::new(),
+            extras: Vec::new(),
+            warnings: Vec::new(),
+            consumed: 0,
+            exclusive_groups: Vec::new(),
+            requires: Vec::new(),
+            required_unless: Vec::new(),
+            config_kvs: Vec::new(),
+            config_flags: Vec::new(),
+            error_index: None,
+            errors: Vec::new(),
+            global_keys: HashSet::new(),
+            raw_values: BTreeMap::new(),
+            occurrences: BTreeMap::new(),
+            setters: Vec::new(),
+        }
+    }
+
+    // Treat tokens like `-5` as positional values unless they match a key
+    pub fn allow_negative_numbers(&mut self, allow: bool) {
+        self.allow_negative_numbers = allow;
+    }
+
+
+    // Accept unambiguous prefixes of long keys, e.g. `--verb` for `--verbose`
+    pub fn allow_abbreviations(&mut self, allow: bool) {
+        self.allow_abbreviations = allow;
+    }
+
+    // Keep parsing past bad tokens and report everything wrong at once, as
+    // ParseError::Multiple if there's more than one. Help and version
+    // requests still stop parsing.
+    pub fn collect_all_errors(&mut self, on: bool) {
+        self.collect_all_errors = on;
+    }
+
+    // Set unknown keys aside in unknown_args() instead of failing, e.g. to
+    // forward them to a child process. Since there's no telling whether an
+    // unknown key takes a value, the next token goes with it unless it starts
+    // with a dash; use `--key=value` to be unambiguous.
+    pub fn ignore_unknown(&mut self, on: bool) {
+        self.ignore_unknown = on;
+    }
+
+    // Stop at the first positional that doesn't fit, instead of failing with
+    // TooManyPositional, and leave it and everything after it in leftover()
+    pub fn allow_leftover(&mut self, allow: bool) {
+        self.allow_leftover = allow;
+    }
+
+    // Keep positionals beyond those registered in extras() instead of failing
+    // with TooManyPositional, e.g. for a list of files. Unlike
+    // allow_leftover(), parsing carries on after them.
+    pub fn collect_extras(&mut self, on: bool) {
+        self.collect_extras = on;
+    }
+
+    // Replace each `@path` token with the whitespace-separated contents of
+    // the file. Tokens from the file are taken as they are, so one that starts
+    // with `@` isn't read in turn. Nothing after `--` is replaced.
+    pub fn response_files(&mut self, on: bool) {
+        self.response_files = on;
+    }
+
+    // Keys may start with any of chars instead of `-`, e.g. "/" for
+    // `/verbose`, or "-/" for either. Long keys and the `--` separator take the
+    // char doubled (`//verbose`, `//`). Help still shows dashes.
+    pub fn prefix_chars(&mut self, chars: &str) {
+        assert!(!chars.is_empty());
+        self.prefix_chars = String::from(chars);
+    }
+
+    // Match keys regardless of case, e.g. `--VERBOSE` for `--verbose`. Short
+    // keys fold too, so `-v` and `-V` are the same key. Must be set before any
+    // args are registered.
+    pub fn case_insensitive(&mut self, on: bool) {
+        assert!(self.kv_keys.is_empty() && self.flag_keys.is_empty() && self.count_keys.is_empty());
+        self.case_insensitive = on;
+    }
+
+    // Enables the built-in `--version`/`-V`
+    pub fn version(&mut self, v: &str) {
+        self.version = Some(String::from(v));
+    }
+
+    // Heads the help text. Defaults to the basename of argv[0] once parsed.
+    pub fn name(&mut self, n: &str) {
+        self.name = Some(String::from(n));
+    }
+
+    // Shown under the name in the help text
+    pub fn about(&mut self, s: &str) {
+        self.about = Some(String::from(s));
+    }
+
+    // A help section for options, listed after the ungrouped ones
+    pub fn add_group(&mut self, name: &str) -> Group {
+        self.groups.push(String::from(name));
+        Group{index: self.groups.len() - 1}
+    }
+
+    // Lists the flag, kv or count arg named `arg` under group in help()
+    pub fn add_to_group(&mut self, group: Group, arg: &str) {
+        assert!(group.index < self.groups.len());
+        self.grouped.insert(String::from(arg), group.index);
+    }
+
+    // Alphabetical by default. The built-in help and version always come last
+    // in insertion order.
+    pub fn help_order(&mut self, order: Order) {
+        self.help_order = order;
+    }
+
+
+    pub fn add_pos_arg<A: PosArgBase>(&mut self, pos_arg: A) -> Handle<A> {
+        assert!(!self.pos_arg_names.contains(pos_arg.name()));
+        if self.subcommands.contains_key(pos_arg.name()) {
+            self.warnings.push(format!("positional `<{}>` has the same name as subcommand `{}`", pos_arg.name(), pos_arg.name()));
+        }
+        // Nothing could ever reach a positional after a variadic one
+        assert!(!self.pos_args.iter().any(|arg| arg.variadic()));
+        self.pos_arg_names.insert(String::from(pos_arg.name()));
+
+        self.pos_args.push(Box::new(pos_arg));
+        Handle::new(ArgKind::Pos, self.pos_args.len() - 1)
+    }
+
+    // Makes the first positional a required `<command>` that must be one of
+    // commands; anything else is ParseError::InvalidChoice. For flat CLIs
+    // that dispatch on a word without a parser per command. Must come before
+    // any other positional.
+    pub fn command_positional(&mut self, commands: &[&str]) -> Handle<CommandArg> {
+        assert!(self.pos_args.is_empty());
+        let commands = commands.iter().map(|c| String::from(*c)).collect();
+        self.add_pos_arg(CommandArg::new(String::from("command"), String::new(), commands))
+    }
+
+    pub fn add_kv_arg<A: KVArgBase>(&mut self, kv_arg: A) -> Handle<A> {
+        let index = self.kv_args.len();
+
+        match kv_arg.long_key() {
+            Some(long) => {
+                self.reserve_key(long, kv_arg.name());
+                assert!(long.len() > 1);
+                self.kv_keys.insert(self.fold_key(long).into_owned(), index);
+            }
+            None => assert!(kv_arg.short_key().is_some()),
+        }
+
+        for alias in kv_arg.aliases() {
+            self.reserve_key(alias, kv_arg.name());
+            assert!(alias.len() > 1);
+            self.kv_keys.insert(self.fold_key(alias).into_owned(), index);
+        }
+
+        if let Some(c) = kv_arg.short_key() {
+            let cs = c.to_string();
+            self.reserve_key(&cs, kv_arg.name());
+            self.kv_keys.insert(self.fold_key(&cs).into_owned(), index);
+        };
+
+        self.option_names.push(String::from(kv_arg.name()));
+        self.kv_args.push(Box::new(kv_arg));
+        Handle::new(ArgKind::KV, index)
+    }
+
+    pub fn add_flag_arg<A: FlagArgBase>(&mut self, flag_arg: A) -> Handle<A> {
+        let index = self.flag_args.len();
+
+        match flag_arg.long_key() {
+            Some(long) => {
+                self.reserve_key(long, flag_arg.name());
+                assert!(long.len() > 1);
+                self.flag_keys.insert(self.fold_key(long).into_owned(), index);
+            }
+            None => assert!(flag_arg.short_key().is_some()),
+        }
+
+        for alias in flag_arg.aliases() {
+            self.reserve_key(alias, flag_arg.name());
+            assert!(alias.len() > 1);
+            self.flag_keys.insert(self.fold_key(alias).into_owned(), index);
+        }
+
+        if let (true, Some(long)) = (flag_arg.negatable(), flag_arg.long_key()) {
+            let negated = format!("no-{}", long);
+            self.reserve_key(&negated, flag_arg.name());
+            self.negated_flag_keys.insert(self.fold_key(&negated).into_owned(), index);
+        }
+
+        if let Some(c) = flag_arg.short_key() {
+            let cs = c.to_string();
+            self.reserve_key(&cs, flag_arg.name());
+            self.flag_keys.insert(self.fold_key(&cs).into_owned(), index);
+        };
+
+        self.option_names.push(String::from(flag_arg.name()));
+        self.flag_args.push(Box::new(flag_arg));
+        Handle::new(ArgKind::Flag, index)
+    }
+
+    pub fn add_count_arg<A: CountArgBase>(&mut self, count_arg: A) -> Handle<A> {
+        let index = self.count_args.len();
+
+        self.reserve_key(count_arg.name(), count_arg.name());
+        assert!(count_arg.name().len() > 1);
+
+        self.count_keys.insert(self.fold_key(count_arg.name()).into_owned(), index);
+
+        if let Some(c) = count_arg.short_key() {
+            let cs = c.to_string();
+            self.reserve_key(&cs, count_arg.name());
+            self.count_keys.insert(self.fold_key(&cs).into_owned(), index);
+        };
+
+        self.option_names.push(String::from(count_arg.name()));
+        self.count_args.push(Box::new(count_arg));
+        Handle::new(ArgKind::Count, index)
+    }
+
+    // Like add_kv_arg(), but parse_into() hands the value (or default) to
+    // setter, e.g. `|val, config: &mut Config| config.jobs = val`. Not called
+    // if there's neither.
+    pub fn add_kv_arg_into<T, C, F>(&mut self, kv_arg: KVArg<T>, setter: F) -> Handle<KVArg<T>>
+        where T: FromStr + 'static,
+            <T as FromStr>::Err: Debug,
+            C: Any,
+            F: Fn(T, &mut C) + 'static {
+
+        let handle = self.add_kv_arg(kv_arg);
+        self.setters.push(Box::new(move |parser, target| {
+            if let Some(val) = parser.arg_mut(handle).val() {
+                setter(val, downcast_target(target));
+            }
+        }));
+        handle
+    }
+
+    // Like add_pos_arg(), with a setter as for add_kv_arg_into()
+    pub fn add_pos_arg_into<T, C, F>(&mut self, pos_arg: PosArg<T>, setter: F) -> Handle<PosArg<T>>
+        where T: FromStr + 'static,
+            <T as FromStr>::Err: Debug,
+            C: Any,
+            F: Fn(T, &mut C) + 'static {
+
+        let handle = self.add_pos_arg(pos_arg);
+        self.setters.push(Box::new(move |parser, target| {
+            if let Some(val) = parser.arg_mut(handle).val() {
+                setter(val, downcast_target(target));
+            }
+        }));
+        handle
+    }
+
+    // Like add_flag_arg(), but parse_into() always calls setter, with whether
+    // the flag was found
+    pub fn add_flag_arg_into<A, C, F>(&mut self, flag_arg: A, setter: F) -> Handle<A>
+        where A: FlagArgBase,
+            C: Any,
+            F: Fn(bool, &mut C) + 'static {
+
+        let handle = self.add_flag_arg(flag_arg);
+        self.setters.push(Box::new(move |parser, target| {
+            setter(parser.arg(handle).found(), downcast_target(target));
+        }));
+        handle
+    }
+
+    // Runs the setters from the add_*_arg_into() methods, in the order the
+    // args were added; call after parsing. Values are taken out, as with
+    // val(). Panics if target isn't the type the setters were given.
+    pub fn parse_into<C: Any>(&mut self, target: &mut C) {
+        let setters = std::mem::take(&mut self.setters);
+        for setter in &setters {
+            setter(self, target);
+        }
+        self.setters = setters;
+    }
+
+    // At most one of the named args may be given
+    pub fn add_mutually_exclusive(&mut self, names: &[&str]) {
+        self.exclusive_groups.push(names.iter().map(|name| String::from(*name)).collect());
+    }
+
+    // If `arg` is given then `requires` must be too, e.g. `--cert` and `--key`
+    pub fn add_requires(&mut self, arg: &str, requires: &str) {
+        self.requires.push((String::from(arg), String::from(requires)));
+    }
+
+    // `arg` is required unless one of alternatives is given, e.g. `--config`
+    // unless `--config-dir`
+    pub fn required_unless_any(&mut self, arg: &str, alternatives: &[&str]) {
+        let alternatives = alternatives.iter().map(|name| String::from(*name)).collect();
+        self.required_unless.push((String::from(arg), alternatives));
+    }
+
+    // Like add_flag_arg(), but also accepted after a subcommand, where it still
+    // sets this parser's arg
+    pub fn add_global_flag<A: FlagArgBase>(&mut self, flag_arg: A) -> Handle<A> {
+        let handle = self.add_flag_arg(flag_arg);
+        let keys = self.flag_keys.iter().chain(self.negated_flag_keys.iter())
+            .filter(|&(_, &index)| index == handle.index)
+            .map(|(key, _)| key.clone());
+        self.global_keys.extend(keys.collect::<Vec<_>>());
+        handle
+    }
+
+    // Like add_kv_arg(), but also accepted after a subcommand, where it still
+    // sets this parser's arg
+    pub fn add_global_kv<A: KVArgBase>(&mut self, kv_arg: A) -> Handle<A> {
+        let handle = self.add_kv_arg(kv_arg);
+        let keys = self.kv_keys.iter()
+            .filter(|&(_, &index)| index == handle.index)
+            .map(|(key, _)| key.clone());
+        self.global_keys.extend(keys.collect::<Vec<_>>());
+        handle
+    }
+
+    // Panics if name is already a subcommand. Sharing a positional's name
+    // is allowed, as the name is never typed, but adds a warning since
+    // help() would show both.
+    pub fn add_subcommand(&mut self, name: &str, sub_parser: Parser) {
+        if self.subcommands.contains_key(name) {
+            panic!("subcommand `{}` is already registered", name);
+        }
+        if self.pos_arg_names.contains(name) {
+            self.warnings.push(format!("subcommand `{}` has the same name as positional `<{}>`", name, name));
+        }
+        self.subcommands.insert(String::from(name), sub_parser);
+    }
+
+    pub fn subcommand(&self, name: &str) -> Option<&Parser> {
+        self.subcommands.get(name)
+    }
+
+    pub fn matched_subcommand(&self) -> Option<&str> {
+        self.matched_subcommand.as_deref()
+    }
+
+    // Runs name, which must already be added, when the first positional
+    // isn't a subcommand or there's none. It's given that positional and
+    // everything after; keys before it are still ours. Our own positionals
+    // are never filled. A `--` is passed on with everything after it, so
+    // `prog -- -x` gives the default `-x` as a positional.
+    pub fn default_subcommand(&mut self, name: &str) {
+        assert!(self.subcommands.contains_key(name));
+        self.default_subcommand = Some(String::from(name));
+    }
+
+    // Like add_subcommand(), named by kind
+    pub fn add_subcommand_kind<K: SubcommandKind>(&mut self, kind: K, sub_parser: Parser) {
+        self.add_subcommand(kind.name(), sub_parser);
+    }
+
+    // matched_subcommand() as a K, e.g. to match on. None if no subcommand
+    // was given or it isn't one of K's.
+    pub fn subcommand_kind<K: SubcommandKind>(&self) -> Option<K> {
+        K::from_name(self.matched_subcommand()?)
+    }
+
+    // Reads `key = value` lines (blank lines and `#` comments are skipped) to
+    // use as defaults for the kv and flag args registered so far. Flags take
+    // `true` or `false`. Anything given on the command line or through an
+    // environment variable takes precedence.
+    pub fn load_defaults(&mut self, path: &Path) -> Result<(), ParseError> {
+        let config_error = |line, msg| ParseError::Config{path: path.display().to_string(), line, msg};
+
+        let contents = std::fs::read_to_string(path).map_err(|e| config_error(None, e.to_string()))?;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, val) = match line.split_once('=') {
+                Some((key, val)) => (key.trim(), val.trim()),
+                None => return Err(config_error(Some(i + 1), format!("expected `key = value`, got `{}`", line))),
+            };
+            let folded = self.fold_key(key);
+            if let Some(&index) = self.kv_keys.get(&*folded) {
+                self.config_kvs.push((index, String::from(val)));
+            } else if let Some(&index) = self.flag_keys.get(&*folded) {
+                match val {
+                    "true" => self.config_flags.push(index),
+                    "false" => {}
+                    _ => return Err(config_error(Some(i + 1), format!("`{}` is a flag; expected true or false", key))),
+                }
+            } else {
+                return Err(config_error(Some(i + 1), format!("unknown key `{}`", key)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Looks up a kv or positional arg's value by name, without its Handle. The
+    // value is parsed again as T, so it needn't be the arg's own type. Gives
+    // the last value if there were several, and None if there were none
+    // (defaults don't count) or it isn't a valid T.
+    pub fn value_of<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.raw_values.get(name)?.last()?.parse().ok()
+    }
+
+    // Like value_of(), but tells a value that isn't a valid T (InvalidValue)
+    // apart from no value (Ok(None))
+    pub fn try_value_of<T>(&self, name: &str) -> Result<Option<T>, ParseError>
+        where T: FromStr, <T as FromStr>::Err: Debug {
+
+        let value = match self.raw_values.get(name).and_then(|vals| vals.last()) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        value.parse().map(Some).map_err(|e| ParseError::InvalidValue{
+            name: String::from(name),
+            value: value.clone(),
+            msg: format!("{:?}", e),
+        })
+    }
+
+    // Names of positionals that weren't given, in registration order
+    pub fn missing_positionals(&self) -> Vec<&str> {
+        self.pos_args.iter()
+            .filter(|arg| !arg.found())
+            .map(|arg| arg.name())
+            .collect()
+    }
+
+    // Tokens after `--` left over once the positional args are filled
+    pub fn trailing(&self) -> &[String] {
+        &self.trailing
+    }
+
+    // Unknown keys, and the values taken with them, as given
+    pub fn unknown_args(&self) -> &[String] {
+        &self.unknown_args
+    }
+
+    // Things worth telling the user that didn't stop parsing, like the use of
+    // a deprecated arg
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // With collect_extras(), positionals there was no arg for
+    pub fn extras(&self) -> &[String] {
+        &self.extras
+    }
+
+    // With allow_leftover(), the tokens left unparsed
+    pub fn leftover(&self) -> &[OsString] {
+        &self.leftover
+    }
+
+    // How many tokens after the program path the last parse got through,
+    // not counting leftover()
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    // Freezes what was parsed into a Matches, e.g. to read from several
+    // threads. Handles don't work on it; look args up by name instead.
+    pub fn into_matches(mut self) -> Matches {
+        let present = self.pos_args.iter().filter(|arg| arg.found()).map(|arg| arg.name())
+            .chain(self.kv_args.iter().filter(|arg| arg.found()).map(|arg| arg.name()))
+            .chain(self.flag_args.iter().filter(|arg| arg.found()).map(|arg| arg.name()))
+            .chain(self.count_args.iter().filter(|arg| arg.found()).map(|arg| arg.name()))
+            .map(String::from)
+            .collect();
+        let subcommand = self.matched_subcommand.take().map(|name| {
+            let sub = self.subcommands.remove(&name).unwrap();
+            (name, Box::new(sub.into_matches()))
+        });
+        Matches{present, values: self.raw_values, trailing: self.trailing, subcommand}
+    }
+
+    // Panics if the handle came from a different parser
+    pub fn arg<A: Any>(&self, handle: Handle<A>) -> &A {
+        let arg: &dyn Any = match handle.kind {
+            ArgKind::Pos => &*self.pos_args[handle.index],
+            ArgKind::KV => &*self.kv_args[handle.index],
+            ArgKind::Flag => &*self.flag_args[handle.index],
+            ArgKind::Count => &*self.count_args[handle.index],
+        };
+        arg.downcast_ref().expect("handle belongs to a different parser")
+    }
+
+    // Panics if the handle came from a different parser
+    pub fn arg_mut<A: Any>(&mut self, handle: Handle<A>) -> &mut A {
+        let arg: &mut dyn Any = match handle.kind {
+            ArgKind::Pos => &mut *self.pos_args[handle.index],
+            ArgKind::KV => &mut *self.kv_args[handle.index],
+            ArgKind::Flag => &mut *self.flag_args[handle.index],
+            ArgKind::Count => &mut *self.count_args[handle.index],
+        };
+        arg.downcast_mut().expect("handle belongs to a different parser")
+    }
+
+    pub fn get<T>(&self, handle: Handle<KVArg<T>>) -> Option<&T>
+        where T: FromStr + 'static,
+            <T as FromStr>::Err: Debug {
+
+        self.arg(handle).get()
+    }
+
+    // Finds the long key that prefix abbreviates, if any. Several keys for the
+    // same arg (i.e. aliases) aren't ambiguous.
+    fn expand_abbreviation(&self, prefix: &str) -> Result<Option<String>, ParseError> {
+        let tables = [
+            (&self.kv_keys, ArgKind::KV, false),
+            (&self.flag_keys, ArgKind::Flag, false),
+            (&self.negated_flag_keys, ArgKind::Flag, true),
+            (&self.count_keys, ArgKind::Count, false),
+        ];
+
+        let mut candidates = Vec::new();
+        for &(keys, kind, negated) in &tables {
+            for (key, &index) in keys {
+                if key.len() > 1 && key.starts_with(prefix) {
+                    candidates.push((key, (kind, index, negated)));
+                }
+            }
+        }
+
+        let first = match candidates.first() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        if candidates.iter().all(|candidate| candidate.1 == first.1) {
+            return Ok(Some(first.0.clone()));
+        }
+
+        let mut keys: Vec<String> = candidates.iter().map(|candidate| candidate.0.clone()).collect();
+        keys.sort();
+        Err(ParseError::AmbiguousKey{input: String::from(prefix), candidates: keys})
+    }
+
+    // Suggests the closest registered long key, if any is close enough.
+    // Otherwise, a key that starts with a long kv key was probably meant to be
+    // followed by its value.
+    fn unknown_key(&self, key: &str) -> ParseError {
+        let folded = self.fold_key(key);
+        let mut long_keys: Vec<&str> = self.kv_keys.keys()
+            .chain(self.flag_keys.keys())
+            .chain(self.negated_flag_keys.keys())
+            .chain(self.count_keys.keys())
+            .map(|k| k.as_str())
+            .filter(|k| k.len() > 1)
+            .collect();
+        long_keys.push("help");
+
+        let max_dist = std::cmp::max(1, folded.chars().count() / 3);
+        let suggestion = long_keys.into_iter()
+            .map(|k| (levenshtein(&folded, k), k))
+            .filter(|&(dist, _)| dist <= max_dist)
+            .min()
+            .map(|(_, k)| String::from(k));
+        if suggestion.is_some() {
+            return ParseError::UnknownKey{key: String::from(key), suggestion};
+        }
+
+        let kv_key = self.kv_keys.keys()
+            .filter(|k| k.len() > 1 && folded.len() > k.len() && folded.starts_with(k.as_str()))
+            .max_by_key(|k| k.len());
+        match kv_key {
+            Some(kv_key) => ParseError::UnseparatedValue{key: String::from(key), kv_key: kv_key.clone()},
+            None => ParseError::UnknownKey{key: String::from(key), suggestion: None},
+        }
+    }
+
+    // Whether the arg with long name `name`, of any kind, was given
+    pub fn is_present(&self, name: &str) -> bool {
+        self.pos_args.iter().any(|arg| arg.name() == name && arg.found())
+            || self.kv_args.iter().any(|arg| arg.name() == name && arg.found())
+            || self.flag_args.iter().any(|arg| arg.name() == name && arg.found())
+            || self.count_args.iter().any(|arg| arg.name() == name && arg.found())
+    }
+
+    // Each arg's name and whether it was given, e.g. for logging.
+    // Positionals come first, then kv args, flags and count args, each in the
+    // order added. Hidden args are included.
+    pub fn iter_results(&self) -> impl Iterator<Item = (&str, bool)> + '_ {
+        let pos = self.pos_args.iter().map(|arg| (arg.name(), arg.found()));
+        let kv = self.kv_args.iter().map(|arg| (arg.name(), arg.found()));
+        let flag = self.flag_args.iter().map(|arg| (arg.name(), arg.found()));
+        let count = self.count_args.iter().map(|arg| (arg.name(), arg.found()));
+        pos.chain(kv).chain(flag).chain(count)
+    }
+
+    // Keys are stored and looked up lowercased in case-insensitive mode
+    fn fold_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(key.to_lowercase())
+        } else {
+            Cow::Borrowed(key)
+        }
+    }
+
+    // Whether `arg` is a key registered with add_global_*()
+    fn is_global(&self, arg: &str) -> bool {
+        let body = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+            Some(body) => body,
+            None => return false,
+        };
+        let key = body.split_once('=').map_or(body, |(key, _)| key);
+        self.global_keys.contains(&*self.fold_key(key))
+    }
+
+    // Panics, naming both args, if key already belongs to one
+    fn reserve_key(&self, key: &str, name: &str) {
+        if let Some(owner) = self.key_owner(key) {
+            panic!("`{}` for `{}` is already taken by `{}`", dashed(key), name, owner);
+        }
+    }
+
+    // Name of the arg that key belongs to, if any
+    fn key_owner(&self, key: &str) -> Option<&str> {
+        let key = &*self.fold_key(key);
+        if let Some(&index) = self.kv_keys.get(key) {
+            Some(self.kv_args[index].name())
+        } else if let Some(&index) = self.flag_keys.get(key).or_else(|| self.negated_flag_keys.get(key)) {
+            Some(self.flag_args[index].name())
+        } else {
+            self.count_keys.get(key).map(|&index| self.count_args[index].name())
+        }
+    }
+
+    fn key_taken(&self, key: &str) -> bool {
+        self.key_owner(key).is_some()
+    }
+
+    // Clears everything parsed so far, including in subcommands, so the parser
+    // can be used on another argv. Registered args and settings are kept.
+    pub fn reset(&mut self) {
+        for arg in &mut self.pos_args { arg.reset(); }
+        for arg in &mut self.kv_args { arg.reset(); }
+        for arg in &mut self.flag_args { arg.reset(); }
+        for arg in &mut self.count_args { arg.reset(); }
+        for sub_parser in self.subcommands.values_mut() { sub_parser.reset(); }
+        self.matched_subcommand = None;
+        self.trailing.clear();
+        self.unknown_args.clear();
+        self.leftover.clear();
+        self.extras.clear();
+        self.warnings.clear();
+        self.consumed = 0;
+        self.error_index = None;
+        self.raw_values.clear();
+        self.occurrences.clear();
+    }
+
+    pub fn parse(&mut self) -> Result<(), ParseError> {
+        self.parse_iter(std::env::args_os())
+    }
+
+    pub fn parse_vec(&mut self, argv: Vec<String>) -> Result<(), LocatedError> {
+        self.parse_located(argv)
+    }
+
+    // parse_iter(), with the error's argv index attached
+    pub fn parse_located<I, S>(&mut self, args: I) -> Result<(), LocatedError>
+        where I: IntoIterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        self.parse_iter(args).map_err(|kind| LocatedError{kind, index: self.error_index})
+    }
+
+    // Splits line roughly like a shell would, then parses it, e.g.
+    // parse_str("prog --name 'a b'"). Meant for tests.
+    pub fn parse_str(&mut self, line: &str) -> Result<(), ParseError> {
+        self.parse_iter(shell_split(line))
+    }
+
+    // Keys must be UTF-8, but values are handed to the args as OsStr, so e.g.
+    // a PathBuf arg can take a non-UTF-8 path
+    pub fn parse_os(&mut self, argv: Vec<OsString>) -> Result<(), ParseError> {
+        self.parse_iter(argv)
+    }
+
+    pub fn parse_iter<I, S>(&mut self, args: I) -> Result<(), ParseError>
+        where I: IntoIterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        if !self.response_files {
+            return self.parse_argv(args);
+        }
+
+        let (argv, origins) = match expand_response_files(args) {
+            Ok(expanded) => expanded,
+            Err((e, index)) => {
+                self.error_index = Some(index);
+                return Err(e);
+            }
+        };
+        let result = self.parse_argv(argv);
+        // Errors point at the `@path` a token came from, or still past the end
+        self.error_index = self.error_index
+            .map(|index| origins.get(index).copied().unwrap_or_else(|| origins.last().map_or(0, |last| last + 1)));
+        result
+    }
+
+    // Parses what it knows and returns the rest in order, without the program
+    // path, for another parser to take, e.g. one picked by a command
+    // positional. Unknown keys are handled as with ignore_unknown(); anything
+    // after `--` is returned after a `--`.
+    pub fn parse_partial<I, S>(&mut self, args: I) -> Result<Vec<String>, ParseError>
+        where I: IntoIterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        let (ignore_unknown, collect_extras) = (self.ignore_unknown, self.collect_extras);
+        self.ignore_unknown = true;
+        self.collect_extras = true;
+        self.partial = true;
+        let result = self.parse_iter(args);
+        self.ignore_unknown = ignore_unknown;
+        self.collect_extras = collect_extras;
+        self.partial = false;
+        result?;
+
+        let mut rest = self.unknown_args.clone();
+        if !self.trailing.is_empty() {
+            rest.push(String::from("--"));
+            rest.extend(self.trailing.iter().cloned());
+        }
+        Ok(rest)
+    }
+
+    fn parse_argv<I, S>(&mut self, args: I) -> Result<(), ParseError>
+        where I: IntoIterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        self.error_index = None;
+        self.errors.clear();
+
+        let mut it = CountingIter::new(args.into_iter());
+        // The first arg is the program path
+        if let Some(path) = it.next() {
+            if self.name.is_none() {
+                let path = Path::new(path.as_ref());
+                self.name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+            }
+        }
+        let result = self.parse_tokens(&mut it);
+        self.consumed = it.count.saturating_sub(1) - self.leftover.len();
+        if result.is_err() && self.error_index.is_none() {
+            // The last token taken is the one that failed
+            self.error_index = Some(it.count - 1);
+        }
+        result?;
+
+        if !self.collect_all_errors {
+            self.apply_env()?;
+            self.apply_config()?;
+            return self.finish().into_iter().next().map_or(Ok(()), Err);
+        }
+
+        let env = self.apply_env();
+        let config = self.apply_config();
+        let finish = self.finish();
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.extend(env.err());
+        errors.extend(config.err());
+        errors.extend(finish);
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().unwrap()),
+            _ => Err(ParseError::Multiple(errors)),
+        }
+    }
+
+    // With collect_all_errors(), sets aside an error from the token at index so
+    // parsing can go on
+    fn note_error(&mut self, result: Result<(), ParseError>, index: usize) -> Result<(), ParseError> {
+        match result {
+            Err(ParseError::HelpRequested) | Err(ParseError::VersionRequested(_)) => result,
+            Err(e) if self.collect_all_errors => {
+                self.error_index.get_or_insert(index);
+                self.errors.push(e);
+                Ok(())
+            }
+            _ => result,
+        }
+    }
+
+    // Index into argv of the token that made the last parse fail. None if
+    // parsing succeeded or failed on a post-parse check like MissingRequired.
+    // argv.len() if a default_subcommand() that was given nothing failed.
+    pub fn error_index(&self) -> Option<usize> {
+        self.error_index
+    }
+
+    fn parse_tokens<I, S>(&mut self, it: &mut CountingIter<I>) -> Result<(), ParseError>
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        let mut pos_args_consumed = 0;
+        let mut options_done = false;
+
+        while let Some(os_arg) = it.next() {
+            let os_arg = os_arg.as_ref();
+            let arg = match os_arg.to_str() {
+                Some(arg) => arg,
+                None if !options_done && os_arg.as_encoded_bytes().starts_with(b"-") => {
+                    let e = ParseError::InvalidUtf8(os_arg.to_string_lossy().into_owned());
+                    self.note_error(Err(e), it.count - 1)?;
+                    continue;
+                },
+                None if self.is_leftover(pos_args_consumed, options_done) => {
+                    self.take_leftover(os_arg, it);
+                    break;
+                },
+                None => {
+                    let result = self.parse_positional(os_arg, &mut pos_args_consumed, options_done);
+                    self.note_error(result, it.count - 1)?;
+                    continue;
+                },
+            };
+
+            let raw = arg;
+            let dashed = self.with_dashes(arg);
+            let arg = dashed.as_deref().unwrap_or(raw);
+            let may_be_key = dashed.is_some();
+
+            // Everything after a bare `--` is positional
+            if arg == "--" && may_be_key && !options_done {
+                if let Some(name) = self.default_subcommand.clone() {
+                    self.parse_subcommand(&name, it.count - 1, Some(os_arg), it)?;
+                    break;
+                }
+                options_done = true;
+                continue;
+            }
+
+            // A bare `-` is a positional value (conventionally stdin)
+            let is_key = !options_done && may_be_key && arg.len() > 1 && arg.starts_with('-')
+                && !(self.allow_negative_numbers && is_negative_number(arg) && !self.key_taken(&arg[1..]));
+
+            if is_key {
+                let index = it.count - 1;
+                let result = match self.parse_key(arg, it) {
+                    Err(ParseError::UnknownKey{..}) | Err(ParseError::UnseparatedValue{..}) if self.ignore_unknown => {
+                        self.pass_unknown(raw, it);
+                        Ok(())
+                    }
+                    result => result,
+                };
+                self.note_error(result, index)?;
+            } else if pos_args_consumed == 0 && !options_done
+                    && (self.subcommands.contains_key(raw) || self.default_subcommand.is_some()) {
+                let index = it.count - 1;
+                if self.subcommands.contains_key(raw) {
+                    self.parse_subcommand(raw, index, None, it)?;
+                } else {
+                    let name = self.default_subcommand.clone().unwrap();
+                    self.parse_subcommand(&name, index, Some(os_arg), it)?;
+                }
+                break;
+            } else if self.is_leftover(pos_args_consumed, options_done) {
+                self.take_leftover(os_arg, it);
+                break;
+            } else {
+                let result = self.parse_positional(os_arg, &mut pos_args_consumed, options_done);
+                self.note_error(result, it.count - 1)?;
+            }
+        }
+
+        if self.matched_subcommand.is_none() {
+            if let Some(name) = self.default_subcommand.clone() {
+                // There's no token to point at, so errors point past the end
+                self.parse_subcommand(&name, it.count, None, it)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The rest of argv belongs to the subcommand; it sees its own name as the
+    // program path. Global args are still ours, wherever they appear. index is
+    // where the subcommand's name is in our argv, or its first token if it's
+    // the default_subcommand() (argv.len() if it has none).
+    fn parse_subcommand<I, S>(&mut self, name: &str, index: usize, first: Option<&OsStr>, it: &mut CountingIter<I>)
+        -> Result<(), ParseError>
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        let mut sub_argv = vec![OsString::from(name)];
+        let mut sub_positions = vec![index]; // Index into our argv of each of sub_argv
+        if let Some(first) = first {
+            sub_argv.push(first.to_os_string());
+            sub_positions.push(index);
+        }
+        let mut sub_options_done = first.is_some_and(|first| first == "--");
+        while let Some(sub_arg) = it.next() {
+            let sub_arg = sub_arg.as_ref();
+            sub_options_done |= sub_arg == "--";
+            match sub_arg.to_str().and_then(|sub_arg| self.with_dashes(sub_arg)) {
+                Some(sub_arg) if !sub_options_done && self.is_global(&sub_arg) => self.parse_key(&sub_arg, it)?,
+                _ => {
+                    sub_argv.push(sub_arg.to_os_string());
+                    sub_positions.push(it.count - 1);
+                },
+            }
+        }
+
+        let sub = self.subcommands.get_mut(name).unwrap();
+        if let Err(e) = sub.parse_os(sub_argv) {
+            // Failures of the subcommand's own post-parse checks point
+            // at the subcommand name, and past its end at past ours
+            let sub_index = sub.error_index.unwrap_or(0);
+            self.error_index = Some(sub_positions.get(sub_index).copied().unwrap_or(it.count));
+            return Err(e);
+        }
+        self.matched_subcommand = Some(String::from(name));
+        Ok(())
+    }
+
+    // Whether a positional now would be one too many, and allow_leftover() is
+    // on. After `--`, extras go to trailing() as usual.
+    fn is_leftover(&self, pos_args_consumed: usize, options_done: bool) -> bool {
+        self.allow_leftover && !options_done && pos_args_consumed >= self.pos_args.len()
+    }
+
+    fn take_leftover<I, S>(&mut self, first: &OsStr, it: &mut CountingIter<I>)
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        self.leftover.push(first.to_os_string());
+        self.leftover.extend(it.map(|arg| arg.as_ref().to_os_string()));
+    }
+
+    // The token as the rest of parsing sees it, with prefix_chars() other than
+    // `-` turned into dashes. None if it can't be a key because it starts with
+    // a dash that isn't a prefix char.
+    fn with_dashes<'a>(&self, arg: &'a str) -> Option<Cow<'a, str>> {
+        let first = match arg.chars().next() {
+            Some(first) if first == '-' || self.prefix_chars.contains(first) => first,
+            _ => return Some(Cow::Borrowed(arg)),
+        };
+        if !self.prefix_chars.contains(first) {
+            return None;
+        }
+        if first == '-' {
+            return Some(Cow::Borrowed(arg));
+        }
+
+        let body = arg.trim_start_matches(first);
+        let dashes = if arg.len() - body.len() >= 2 { "--" } else { "-" };
+        // Anything past two is part of the key, as with `---x`
+        let extra = &arg[first.len_utf8() * dashes.len()..arg.len() - body.len()];
+        Some(Cow::Owned(format!("{}{}{}", dashes, extra, body)))
+    }
+
+    // Counts a kv or count arg being given, if it has a max_occurs()
+    fn note_occurrence(&mut self, kind: ArgKind, index: usize) -> Result<(), ParseError> {
+        let (name, max) = match kind {
+            ArgKind::KV => (self.kv_args[index].name(), self.kv_args[index].max_occurs()),
+            ArgKind::Count => (self.count_args[index].name(), self.count_args[index].max_occurs()),
+            _ => unreachable!(),
+        };
+        let max = match max {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let name = String::from(name);
+        let occurrences = self.occurrences.entry(name.clone()).or_insert(0);
+        *occurrences += 1;
+        if *occurrences > max {
+            return Err(ParseError::TooManyOccurrences{name, max});
+        }
+        Ok(())
+    }
+
+    // The values after the first for a kv arg taking more than one
+    fn parse_extra_values<I, S>(&mut self, index: usize, key: &str, it: &mut CountingIter<I>) -> Result<(), ParseError>
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        let allow_hyphen_values = self.kv_args[index].allow_hyphen_values();
+        for _ in 1..self.kv_args[index].arity() {
+            let next = self.next_value(it, allow_hyphen_values)
+                .ok_or_else(|| ParseError::MissingValue(String::from(key)))?;
+            parse_kv_value(&mut *self.kv_args[index], next.as_ref(), &mut self.raw_values)?;
+        }
+        Ok(())
+    }
+
+    // Sets an unknown key aside, with what's probably its value
+    fn pass_unknown<I, S>(&mut self, arg: &str, it: &mut CountingIter<I>)
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        self.unknown_args.push(String::from(arg));
+        if arg.contains('=') {
+            return;
+        }
+        let takes_next = match it.peek().map(|next| next.as_ref().to_str()) {
+            Some(Some(next)) => !self.with_dashes(next).is_some_and(|next| next.starts_with('-')),
+            _ => false,
+        };
+        if takes_next {
+            let next = it.next().unwrap();
+            self.unknown_args.push(String::from(next.as_ref().to_str().unwrap()));
+        }
+    }
+
+    // Takes the next token as a kv value, unless it's one of our keys, e.g. the
+    // `--verbose` in `--output --verbose`
+    fn next_value<I, S>(&self, it: &mut CountingIter<I>, allow_hyphen_values: bool) -> Option<S>
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        let is_key = match it.peek()?.as_ref().to_str().and_then(|next| self.with_dashes(next)) {
+            Some(next) => !allow_hyphen_values && self.is_known_key(&next),
+            None => false,
+        };
+        if is_key {
+            None
+        } else {
+            it.next()
+        }
+    }
+
+    // Whether `arg` would be parsed as a key we know, including bundles
+    // starting with one and the built-in help and version
+    fn is_known_key(&self, arg: &str) -> bool {
+        let (body, long) = match arg.strip_prefix("--") {
+            Some(body) => (body, true),
+            None => match arg.strip_prefix('-') {
+                Some(body) => (body, false),
+                None => return false,
+            },
+        };
+        let key = body.split_once('=').map_or(body, |(key, _)| key);
+        // A single dash may start a bundle, so only its first char matters
+        let key = if long { key } else { &key[..key.chars().next().map_or(0, char::len_utf8)] };
+
+        // Folded as in parse_key(), so the built-in keys match the same spellings
+        let key = &*self.fold_key(key);
+        self.key_taken(key)
+            || key == "help" || key == "h"
+            || (self.version.is_some() && (key == "version" || key == self.fold_key("V")))
+    }
+
+    // Handles a single option token, taking its value from `it` if needed
+    fn parse_key<I, S>(&mut self, arg: &str, it: &mut CountingIter<I>) -> Result<(), ParseError>
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        let body = match arg.strip_prefix("--") {
+            Some(long) => long,
+            None => &arg[1..],
+        };
+
+        // `--key=value` carries its value inline; everything after the
+        // first `=` belongs to the value
+        let (key, inline_val) = match body.split_once('=') {
+            Some((key, val)) => (key, Some(val)),
+            None => (body, None),
+        };
+
+        let folded = self.fold_key(key);
+        let key = &*folded;
+
+        let expanded;
+        let key = if self.allow_abbreviations && arg.starts_with("--") && !self.key_taken(key) {
+            match self.expand_abbreviation(key)? {
+                Some(full) => {
+                    expanded = full;
+                    &expanded[..]
+                },
+                None => key,
+            }
+        } else {
+            key
+        };
+
+        if let Some(&index) = self.kv_keys.get(key) {
+            let kv_arg = &*self.kv_args[index];
+            if kv_arg.found() && !kv_arg.multiple() && !kv_arg.override_mode() {
+                return Err(ParseError::DuplicateArg(String::from(key)));
+            }
+            warn_deprecated(&mut self.warnings, key, kv_arg.deprecated());
+            let allow_hyphen_values = kv_arg.allow_hyphen_values();
+            self.note_occurrence(ArgKind::KV, index)?;
+            let kv_arg = &*self.kv_args[index];
+            check_value_style(key, kv_arg.value_style(), inline_val.is_some())?;
+            let next;
+            let val = match inline_val {
+                Some(val) => OsStr::new(val),
+                None => {
+                    next = self.next_value(it, allow_hyphen_values)
+                        .ok_or_else(|| ParseError::MissingValue(String::from(key)))?;
+                    next.as_ref()
+                },
+            };
+            parse_kv_value(&mut *self.kv_args[index], val, &mut self.raw_values)?;
+            self.parse_extra_values(index, key, it)?;
+        } else if let Some(&index) = self.flag_keys.get(key) {
+            let flag_arg = &mut self.flag_args[index];
+            if inline_val.is_some() && !flag_arg.optional_value() {
+                return Err(ParseError::FlagTakesNoValue(String::from(key)));
+            }
+            if flag_arg.found() {
+                return Err(ParseError::DuplicateArg(String::from(key)));
+            }
+            warn_deprecated(&mut self.warnings, key, flag_arg.deprecated());
+            match inline_val {
+                Some(val) => flag_arg.parse_value(val).map_err(|msg| ParseError::InvalidValue{
+                    name: String::from(flag_arg.name()),
+                    value: String::from(val),
+                    msg,
+                })?,
+                None => flag_arg.parse(),
+            }
+        } else if let Some(&index) = self.negated_flag_keys.get(key) {
+            let flag_arg = &mut self.flag_args[index];
+            if inline_val.is_some() {
+                return Err(ParseError::FlagTakesNoValue(String::from(key)));
+            }
+            if flag_arg.found() {
+                return Err(ParseError::DuplicateArg(String::from(key)));
+            }
+            warn_deprecated(&mut self.warnings, key, flag_arg.deprecated());
+            flag_arg.parse_negated();
+        } else if let Some(&index) = self.count_keys.get(key) {
+            if inline_val.is_some() {
+                return Err(ParseError::FlagTakesNoValue(String::from(key)));
+            }
+            self.note_occurrence(ArgKind::Count, index)?;
+            self.count_args[index].parse();
+        } else if key == "help" || key == "h" {
+            // Built-in help, unless the user registered these keys themselves
+            return Err(ParseError::HelpRequested);
+        } else if (key == "version" || key == self.fold_key("V")) && self.version.is_some() {
+            // Built-in version, likewise
+            return Err(ParseError::VersionRequested(self.version.clone().unwrap()));
+        } else if !arg.starts_with("--") && body.chars().count() > 1 {
+            self.parse_short_bundle(body, it)?;
+        } else {
+            return Err(self.unknown_key(body));
+        }
+
+        Ok(())
+    }
+
+    fn parse_positional(&mut self, arg: &OsStr, pos_args_consumed: &mut usize, options_done: bool)
+        -> Result<(), ParseError> {
+
+        if *pos_args_consumed >= self.pos_args.len() {
+            if !options_done && !self.collect_extras {
+                return Err(ParseError::TooManyPositional);
+            }
+            let arg = arg.to_str().ok_or_else(|| ParseError::InvalidUtf8(arg.to_string_lossy().into_owned()))?;
+            if options_done {
+                self.trailing.push(String::from(arg));
+            } else if self.partial {
+                self.unknown_args.push(String::from(arg));
+            } else {
+                self.extras.push(String::from(arg));
+            }
+            return Ok(());
+        }
+
+        let pos_arg = &mut self.pos_args[*pos_args_consumed];
+        if let Some(choices) = pos_arg.choices() {
+            if !choices.iter().any(|c| c.as_str() == arg) {
+                return Err(ParseError::InvalidChoice{
+                    name: String::from(pos_arg.name()),
+                    value: arg.to_string_lossy().into_owned(),
+                    allowed: choices.to_vec(),
+                });
+            }
+        }
+        pos_arg.parse_os(arg).map_err(|msg| ParseError::InvalidValue{
+            name: String::from(pos_arg.name()),
+            value: arg.to_string_lossy().into_owned(),
+            msg,
+        })?;
+        self.raw_values.entry(String::from(pos_arg.name())).or_default().push(arg.to_string_lossy().into_owned());
+        if !pos_arg.variadic() {
+            *pos_args_consumed += 1;
+        }
+
+        Ok(())
+    }
+
+    // Command line values take precedence, so only unset args are filled in
+    fn apply_env(&mut self) -> Result<(), ParseError> {
+        for kv_arg in &mut self.kv_args {
+            let env_var = match kv_arg.env() {
+                Some(env_var) if !kv_arg.found() => String::from(env_var),
+                _ => continue,
+            };
+            if let Some(val) = std::env::var_os(env_var) {
+                parse_kv_value(&mut **kv_arg, &val, &mut self.raw_values)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like apply_env(), but for load_defaults(), which has lower precedence
+    fn apply_config(&mut self) -> Result<(), ParseError> {
+        for (index, val) in &self.config_kvs {
+            let kv_arg = &mut *self.kv_args[*index];
+            if !kv_arg.found() {
+                parse_kv_value(kv_arg, OsStr::new(val), &mut self.raw_values)?;
+            }
+        }
+        for &index in &self.config_flags {
+            let flag_arg = &mut self.flag_args[index];
+            if !flag_arg.found() {
+                flag_arg.parse();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Post-parse validation. Everything wrong, in order of precedence.
+    fn finish(&self) -> Vec<ParseError> {
+        let mut errors = Vec::new();
+
+        // Positionals fill in order, so the first missing one says how many
+        // were short
+        if let Some(pos_arg) = self.pos_args.iter().find(|arg| arg.required() && !arg.found()) {
+            errors.push(ParseError::MissingPositional(String::from(pos_arg.name())));
+        }
+
+        let mut missing = Vec::new();
+        for kv_arg in &self.kv_args {
+            if kv_arg.required() && !kv_arg.found() {
+                missing.push(String::from(kv_arg.name()));
+            }
+        }
+        for (arg, alternatives) in &self.required_unless {
+            if !self.is_present(arg) && !alternatives.iter().any(|name| self.is_present(name)) && !missing.contains(arg) {
+                missing.push(arg.clone());
+            }
+        }
+
+        if !missing.is_empty() {
+            errors.push(ParseError::MissingRequired(missing));
+        }
+
+        for group in &self.exclusive_groups {
+            let mut found = group.iter().filter(|name| self.is_present(name));
+            if let (Some(a), Some(b)) = (found.next(), found.next()) {
+                errors.push(ParseError::Conflict{a: a.clone(), b: b.clone()});
+            }
+        }
+
+        for (arg, requires) in &self.requires {
+            if self.is_present(arg) && !self.is_present(requires) {
+                errors.push(ParseError::MissingDependency{arg: arg.clone(), requires: requires.clone()});
+            }
+        }
+
+        errors
+    }
+
+    pub fn help(&self) -> String {
+        self.render_help(false)
+    }
+
+    // help() with ANSI styling, if stdout is a terminal (see ColorChoice)
+    #[cfg(feature = "color")]
+    pub fn help_colored(&self) -> String {
+        self.render_help(use_color(std::io::stdout().is_terminal()))
+    }
+
+    // help() plus each arg's long description
+    pub fn help_verbose(&self) -> String {
+        self.layout_help(&DefaultHelpFormatter::default(), true)
+    }
+
+    fn render_help(&self, color: bool) -> String {
+        self.format_help(&DefaultHelpFormatter{color})
+    }
+
+    // The help text laid out by fmt, e.g. to produce Markdown instead
+    pub fn format_help(&self, fmt: &dyn HelpFormatter) -> String {
+        self.layout_help(fmt, false)
+    }
+
+    // With verbose, long descriptions follow desc on their own lines
+    fn layout_help(&self, fmt: &dyn HelpFormatter, verbose: bool) -> String {
+        let describe = |desc: &str, long_desc: Option<&str>| match long_desc {
+            Some(long_desc) if verbose => format!("{}\n{}", desc, long_desc),
+            _ => String::from(desc),
+        };
+
+        let pos_rows: Vec<(String, String)> = self.pos_args.iter()
+            .filter(|arg| !arg.hidden())
+            .map(|arg| {
+                let mut desc = describe(arg.desc(), arg.long_desc());
+                if let Some(choices) = arg.choices() {
+                    if !desc.is_empty() { desc.push(' '); }
+                    desc.push_str(&format!("[possible values: {}]", choices.join(", ")));
+                }
+                (pos_help_name(&**arg), desc)
+            })
+            .collect();
+
+        // Flags and kv args are listed together, sorted by long name unless
+        // help_order says otherwise
+        let mut opt_rows = BTreeMap::new();
+        for arg in self.kv_args.iter().filter(|arg| !arg.hidden()) {
+            let keys = format!("{} {}", help_keys(arg.long_key(), arg.short_key()), value_placeholder(&**arg));
+            let mut desc = describe(arg.desc(), arg.long_desc());
+            if let Some(choices) = arg.choices() {
+                desc.push_str(&format!(" [possible values: {}]", choices.join(", ")));
+            }
+            if let Some(default) = arg.default_value() {
+                desc.push_str(&format!(" [default: {}]", default));
+            }
+            push_help_aliases(&mut desc, arg.aliases());
+            opt_rows.insert(String::from(arg.name()), (keys, desc));
+        }
+        for arg in self.flag_args.iter().filter(|arg| !arg.hidden()) {
+            let long = match arg.long_key() {
+                Some(long) if arg.negatable() => Some(format!("{} / --no-{}", long, long)),
+                Some(long) if arg.optional_value() => Some(format!("{}[=<{}>]", long, long.to_uppercase())),
+                long => long.map(String::from),
+            };
+            let keys = help_keys(long.as_deref(), arg.short_key());
+            let mut desc = describe(arg.desc(), arg.long_desc());
+            push_help_aliases(&mut desc, arg.aliases());
+            opt_rows.insert(String::from(arg.name()), (keys, desc));
+        }
+        for arg in &self.count_args {
+            let keys = help_keys(Some(arg.name()), arg.short_key());
+            opt_rows.insert(String::from(arg.name()), (keys, String::from(arg.desc())));
+        }
+        if !self.key_taken("help") {
+            let short_key = if self.key_taken("h") {
+                None
+            } else {
+                Some('h')
+            };
+            opt_rows.insert(String::from("help"), (help_keys(Some("help"), short_key), String::from("print this help")));
+        }
+        if self.version.is_some() && !self.key_taken("version") {
+            let short_key = if self.key_taken("V") {
+                None
+            } else {
+                Some('V')
+            };
+            opt_rows.insert(String::from("version"), (help_keys(Some("version"), short_key), String::from("print version")));
+        }
+        let opt_rows: Vec<(String, (String, String))> = match self.help_order {
+            Order::Alphabetical => opt_rows.into_iter().collect(),
+            Order::Insertion => {
+                let mut rows: Vec<(String, (String, String))> = self.option_names.iter()
+                    .filter_map(|name| opt_rows.remove_entry(name))
+                    .collect();
+                rows.extend(["help", "version"].iter().filter_map(|name| opt_rows.remove_entry(*name)));
+                rows
+            }
+        };
+
+        // Ungrouped options first, then each group in the order added
+        let mut sections: Vec<(&str, Vec<(String, String)>)> = vec![("options", Vec::new())];
+        sections.extend(self.groups.iter().map(|group| (group.as_str(), Vec::new())));
+        for (name, row) in opt_rows {
+            let section = self.grouped.get(&name).map_or(0, |group| group + 1);
+            sections[section].1.push(row);
+        }
+
+        let width = pos_rows.iter().chain(sections.iter().flat_map(|(_, rows)| rows.iter()))
+            .map(|(keys, _)| keys.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut out = fmt.header(self.name.as_deref(), self.about.as_deref());
+
+        out.push_str(&fmt.usage(&self.usage_words()));
+
+        if !pos_rows.is_empty() {
+            out.push_str(&fmt.section("arguments"));
+            for (keys, desc) in &pos_rows {
+                out.push_str(&fmt.row(keys, desc, width));
+            }
+        }
+        for (title, rows) in sections.iter().filter(|(_, rows)| !rows.is_empty()) {
+            out.push_str(&fmt.section(title));
+            for (keys, desc) in rows {
+                out.push_str(&fmt.row(keys, desc, width));
+            }
+        }
+
+        out
+    }
+
+    // E.g. ["[options]", "<input>"]; "[options]" only if help() lists any
+    fn usage_words(&self) -> Vec<String> {
+        let mut words = Vec::new();
+        let any_options = self.kv_args.iter().any(|arg| !arg.hidden())
+            || self.flag_args.iter().any(|arg| !arg.hidden())
+            || !self.count_args.is_empty()
+            || !self.key_taken("help")
+            || (self.version.is_some() && !self.key_taken("version"));
+        if any_options {
+            words.push(String::from("[options]"));
+        }
+        // Required options are spelled out, with their value
+        for arg in self.kv_args.iter().filter(|arg| arg.required() && !arg.hidden()) {
+            let key = match (arg.long_key(), arg.short_key()) {
+                (Some(long), _) => format!("--{}", long),
+                (None, Some(c)) => format!("-{}", c),
+                (None, None) => continue,
+            };
+            words.push(format!("{} {}", key, value_placeholder(&**arg)));
+        }
+        for arg in self.pos_args.iter().filter(|arg| !arg.hidden()) {
+            words.push(pos_help_name(&**arg));
+        }
+        words
+    }
+
+    // Just the usage line of help(), e.g. "usage: [options] <input>\n"
+    pub fn usage(&self) -> String {
+        DefaultHelpFormatter::default().usage(&self.usage_words())
+    }
+
+    // err followed by the usage line, the way most tools report a bad
+    // command line. Styled if stderr is a terminal (see ColorChoice).
+    pub fn error_with_usage(&self, err: &ParseError) -> String {
+        self.render_error_with_usage(err, use_color(std::io::stderr().is_terminal()))
+    }
+
+    fn render_error_with_usage(&self, err: &ParseError, color: bool) -> String {
+        format!("{}\n{}", err.paint(color), DefaultHelpFormatter{color}.usage(&self.usage_words()))
+    }
+
+    // Every arg that help() would list, for tools that render their own help
+    // or completions. Grouped by kind, in registration order.
+    pub fn describe(&self) -> Vec<ArgSpec> {
+        let mut specs = Vec::new();
+        for arg in self.pos_args.iter().filter(|arg| !arg.hidden()) {
+            specs.push(ArgSpec{name: String::from(arg.name()), short_key: None, kind: ArgKind::Pos,
+                default: None, required: arg.required(), desc: String::from(arg.desc())});
+        }
+        for arg in self.kv_args.iter().filter(|arg| !arg.hidden()) {
+            specs.push(ArgSpec{name: String::from(arg.name()), short_key: arg.short_key(), kind: ArgKind::KV,
+                default: arg.default_value(), required: arg.required(), desc: String::from(arg.desc())});
+        }
+        for arg in self.flag_args.iter().filter(|arg| !arg.hidden()) {
+            specs.push(ArgSpec{name: String::from(arg.name()), short_key: arg.short_key(), kind: ArgKind::Flag,
+                default: None, required: false, desc: String::from(arg.desc())});
+        }
+        for arg in &self.count_args {
+            specs.push(ArgSpec{name: String::from(arg.name()), short_key: arg.short_key(), kind: ArgKind::Count,
+                default: None, required: false, desc: String::from(arg.desc())});
+        }
+        specs
+    }
+
+    // describe() as a JSON array of objects
+    pub fn to_json(&self) -> String {
+        let opt_json = |s: Option<String>| s.map_or(String::from("null"), |s| json_str(&s));
+        let specs: Vec<String> = self.describe().into_iter().map(|spec| {
+            let kind = match spec.kind {
+                ArgKind::Pos => "pos",
+                ArgKind::KV => "kv",
+                ArgKind::Flag => "flag",
+                ArgKind::Count => "count",
+            };
+            format!("{{\"name\":{},\"short\":{},\"kind\":\"{}\",\"default\":{},\"required\":{},\"desc\":{}}}",
+                json_str(&spec.name), opt_json(spec.short_key.map(String::from)), kind,
+                opt_json(spec.default), spec.required, json_str(&spec.desc))
+        }).collect();
+        format!("[{}]", specs.join(","))
+    }
+
+    // A completion script for the program offering the keys (after a dash)
+    // and subcommands. Hidden args are left out. The program is name(), or
+    // else the basename of this process's argv[0].
+    pub fn completions(&self, shell: Shell) -> String {
+        let bin_name = match &self.name {
+            Some(name) => name.clone(),
+            None => std::env::args_os().next()
+                .and_then(|path| Path::new(&path).file_name().map(|name| name.to_string_lossy().into_owned()))
+                .unwrap_or_default(),
+        };
+        self.completions_for(shell, &bin_name)
+    }
+
+    // completions() for bin_name instead, e.g. when installed under another
+    // name
+    pub fn completions_for(&self, shell: Shell, bin_name: &str) -> String {
+        let mut long_keys = Vec::new();
+        let mut short_keys = Vec::new();
+        {
+            let mut push_key = |key: &str| if key.chars().count() > 1 {
+                long_keys.push(format!("--{}", key));
+            } else {
+                short_keys.push(format!("-{}", key));
+            };
+            for (key, &index) in &self.kv_keys {
+                if !self.kv_args[index].hidden() { push_key(key); }
+            }
+            for (key, &index) in self.flag_keys.iter().chain(self.negated_flag_keys.iter()) {
+                if !self.flag_args[index].hidden() { push_key(key); }
+            }
+            for key in self.count_keys.keys() {
+                push_key(key);
+            }
+            if !self.key_taken("help") { push_key("help"); }
+            if !self.key_taken("h") { push_key("h"); }
+            if self.version.is_some() {
+                if !self.key_taken("version") { push_key("version"); }
+                if !self.key_taken("V") { push_key("V"); }
+            }
+        }
+        long_keys.sort();
+        short_keys.sort();
+        let keys = long_keys.iter().chain(short_keys.iter())
+            .map(|k| k.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let subcommands = self.subcommands.keys()
+            .map(|k| k.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let func: String = bin_name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        match shell {
+            Shell::Bash => format!(concat!(
+                "_{func}() {{\n",
+                "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n",
+                "    if [[ \"$cur\" == -* ]]; then\n",
+                "        COMPREPLY=($(compgen -W \"{keys}\" -- \"$cur\"))\n",
+                "    else\n",
+                "        COMPREPLY=($(compgen -W \"{subcommands}\" -- \"$cur\"))\n",
+                "    fi\n",
+                "}}\n",
+                "complete -o default -F _{func} {bin}\n"),
+                func = func, keys = keys, subcommands = subcommands, bin = bin_name),
+            Shell::Zsh => format!(concat!(
+                "#compdef {bin}\n",
+                "\n",
+                "_{func}() {{\n",
+                "    if [[ \"$PREFIX\" == -* ]]; then\n",
+                "        compadd -- {keys}\n",
+                "    else\n",
+                "        compadd -- {subcommands}\n",
+                "        _files\n",
+                "    fi\n",
+                "}}\n",
+                "\n",
+                "_{func} \"$@\"\n"),
+                func = func, keys = keys, subcommands = subcommands, bin = bin_name),
+        }
+    }
+
+    // Handles bundled short keys, e.g. `-abc` as `-a -b -c`. A kv short key
+    // takes its value from, in order of precedence: an `=` right after it
+    // (`-n=5`, `-vn=5`), the rest of the token (`-n5`), or the next token if
+    // it's the last in the bundle (`-n 5`).
+    fn parse_short_bundle<I, S>(&mut self, body: &str, it: &mut CountingIter<I>) -> Result<(), ParseError>
+        where I: Iterator<Item = S>,
+            S: AsRef<OsStr> {
+
+        for (i, c) in body.char_indices() {
+            let key = self.fold_key(&c.to_string()).into_owned();
+            if let Some(&index) = self.flag_keys.get(&key) {
+                let flag_arg = &mut self.flag_args[index];
+                if flag_arg.found() {
+                    return Err(ParseError::DuplicateArg(key));
+                }
+                warn_deprecated(&mut self.warnings, &key, flag_arg.deprecated());
+                flag_arg.parse();
+            } else if let Some(&index) = self.count_keys.get(&key) {
+                self.note_occurrence(ArgKind::Count, index)?;
+                self.count_args[index].parse();
+            } else if let Some(&index) = self.kv_keys.get(&key) {
+                let kv_arg = &*self.kv_args[index];
+                if kv_arg.found() && !kv_arg.multiple() && !kv_arg.override_mode() {
+                    return Err(ParseError::DuplicateArg(key));
+                }
+                warn_deprecated(&mut self.warnings, &key, kv_arg.deprecated());
+                let allow_hyphen_values = kv_arg.allow_hyphen_values();
+                self.note_occurrence(ArgKind::KV, index)?;
+                let kv_arg = &*self.kv_args[index];
+                let rest = &body[i + c.len_utf8()..];
+                check_value_style(&key, kv_arg.value_style(), !rest.is_empty())?;
+                let next;
+                let val = if let Some(rest) = rest.strip_prefix('=') {
+                    OsStr::new(rest)
+                } else if rest.is_empty() {
+                    next = self.next_value(it, allow_hyphen_values).ok_or_else(|| ParseError::MissingValue(key.clone()))?;
+                    next.as_ref()
+                } else {
+                    OsStr::new(rest)
+                };
+                let kv_arg = &mut *self.kv_args[index];
+                parse_kv_value(kv_arg, val, &mut self.raw_values)?;
+                return self.parse_extra_values(index, &key, it);
+            } else {
+                return Err(self.unknown_key(body));
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+
+
+
+// Counts the tokens taken from argv, so errors can say which one failed
+struct CountingIter<I: Iterator> {
+    inner: I,
+    count: usize,
+    peeked: Option<Option<I::Item>>,
+}
+
+impl<I: Iterator> CountingIter<I> {
+    fn new(inner: I) -> Self {
+        Self{inner, count: 0, peeked: None}
+    }
+
+    // Doesn't count as taken until next()
+    fn peek(&mut self) -> Option<&I::Item> {
+        let inner = &mut self.inner;
+        self.peeked.get_or_insert_with(|| inner.next()).as_ref()
+    }
+}
+
+impl<I: Iterator> Iterator for CountingIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        let item = match self.peeked.take() {
+            Some(item) => item,
+            None => self.inner.next(),
+        };
+        if item.is_some() {
+            self.count += 1;
+        }
+        item
+    }
+}
+
+
+
+
+// A struct that can be filled in straight from the command line. Normally
+// implemented with #[derive(Args)] (see the "derive" feature).
+pub trait Args: Sized {
+    fn parse_iter<I, S>(args: I) -> Result<Self, ParseError>
+        where I: IntoIterator<Item = S>, S: AsRef<OsStr>;
+
+    fn parse() -> Result<Self, ParseError> { Self::parse_iter(std::env::args_os()) }
+
+    // A Parser with the same args as parse_iter() uses, e.g. to print help
+    // after ParseError::HelpRequested
+    fn command() -> Parser;
+
+    fn help() -> String { Self::command().help() }
+}
+
+
+
+
+
+// Also records the value in `raw_values` for Parser::value_of()
+fn parse_kv_value(kv_arg: &mut dyn KVArgBase, val: &OsStr, raw_values: &mut BTreeMap<String, Vec<String>>)
+    -> Result<(), ParseError> {
+
+    if let Some(choices) = kv_arg.choices() {
+        if !choices.iter().any(|c| c.as_str() == val) {
+            return Err(ParseError::InvalidChoice{
+                name: String::from(kv_arg.name()),
+                value: val.to_string_lossy().into_owned(),
+                allowed: choices.to_vec(),
+            });
+        }
+    }
+
+    kv_arg.parse_os(val).map_err(|msg| ParseError::InvalidValue{
+        name: String::from(kv_arg.name()),
+        value: val.to_string_lossy().into_owned(),
+        msg,
+    })?;
+
+    kv_arg.validate()?;
+
+    raw_values.entry(String::from(kv_arg.name())).or_default().push(val.to_string_lossy().into_owned());
+    Ok(())
+}
+
+fn os_to_str(s: &OsStr) -> Result<&str, String> {
+    s.to_str().ok_or_else(|| format!("invalid UTF-8 {:?}", s))
+}
+
+// Special-cases PathBuf and OsString values, which needn't be UTF-8, and bool,
+// which takes more spellings than bool::from_str()
+fn from_os_str<T>(s: &OsStr) -> Result<T, String>
+    where T: FromStr + 'static,
+        <T as FromStr>::Err: Debug {
+
+    let os_val: Box<dyn Any> = if TypeId::of::<T>() == TypeId::of::<PathBuf>() {
+        Box::new(PathBuf::from(s))
+    } else if TypeId::of::<T>() == TypeId::of::<OsString>() {
+        Box::new(s.to_os_string())
+    } else if TypeId::of::<T>() == TypeId::of::<bool>() {
+        Box::new(parse_bool(os_to_str(s)?)?)
+    } else {
+        return T::from_str(os_to_str(s)?).map_err(|e| format!("{:?}", e));
+    };
+
+    Ok(*os_val.downcast().unwrap())
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(format!("expected true/false, 1/0 or yes/no, got `{}`", s)),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().map_err(|_| format!("expected a number followed by a unit, got `{}`", s))?;
+    let secs = match unit {
+        "ms" => num / 1000.0,
+        "s" => num,
+        "m" => num * 60.0,
+        "h" => num * 60.0 * 60.0,
+        "" => return Err(format!("missing unit (ms, s, m or h) in `{}`", s)),
+        _ => return Err(format!("unknown unit `{}`; expected ms, s, m or h", unit)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+
+    // Single row of the edit distance table
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev_diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = prev_diag + if ca == cb { 0 } else { 1 };
+            prev_diag = row[j + 1];
+            row[j + 1] = std::cmp::min(substitute, std::cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+// For Parser::response_files(). Also gives the index into args of the token
+// each one came from, or, on failure, of the `@path` that couldn't be read.
+fn expand_response_files<I, S>(args: I) -> Result<(Vec<OsString>, Vec<usize>), (ParseError, usize)>
+    where I: IntoIterator<Item = S>,
+        S: AsRef<OsStr> {
+
+    let mut argv = Vec::new();
+    let mut origins = Vec::new();
+    let mut options_done = false;
+    for (i, arg) in args.into_iter().enumerate() {
+        let arg = arg.as_ref();
+        let path = match arg.to_str() {
+            Some(arg) if i > 0 && !options_done && arg.len() > 1 => arg.strip_prefix('@'),
+            _ => None,
+        };
+        options_done |= arg == "--";
+
+        let path = match path {
+            Some(path) => path,
+            None => {
+                argv.push(arg.to_os_string());
+                origins.push(i);
+                continue;
+            }
+        };
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| (ParseError::ResponseFile{path: String::from(path), msg: e.to_string()}, i))?;
+        for token in contents.split_whitespace() {
+            argv.push(OsString::from(token));
+            origins.push(i);
+        }
+    }
+
+    Ok((argv, origins))
+}
+
+// Splits on delimiter, except where it follows a backslash. `\\` is a
+// backslash; any other backslash is kept.
+fn split_escaped(s: &str, delimiter: char) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && (chars.peek() == Some(&delimiter) || chars.peek() == Some(&'\\')) {
+            pieces.last_mut().unwrap().extend(chars.next());
+        } else if c == delimiter {
+            pieces.push(String::new());
+        } else {
+            pieces.last_mut().unwrap().push(c);
+        }
+    }
+    pieces
+}
+
+// Splits on unquoted whitespace. Single quotes keep everything literally;
+// within double quotes or bare, a backslash escapes the next char.
+fn shell_split(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_token = false; // So '' is a token
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => token.push(c),
+            (_, '\\') => {
+                in_token = true;
+                token.extend(chars.next());
+            }
+            (Some(_), c) => token.push(c),
+            (None, '\'') | (None, '"') => {
+                in_token = true;
+                quote = Some(c);
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            (None, c) => {
+                in_token = true;
+                token.push(c);
+            }
+        }
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn warn_deprecated(warnings: &mut Vec<String>, key: &str, note: Option<&str>) {
+    if let Some(note) = note {
+        warnings.push(format!("`{}` is deprecated: {}", dashed(key), note));
+    }
+}
+
+fn is_negative_number(s: &str) -> bool {
+    s.strip_prefix('-').is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit() || c == '.'))
+        && f64::from_str(s).is_ok()
+}
+
+fn downcast_target<C: Any>(target: &mut dyn Any) -> &mut C {
+    target.downcast_mut().expect("parse_into() given a different type than its setters")
+}
+
+// E.g. `<FILE>`, from value_name() or the uppercased name
+fn value_placeholder(arg: &dyn KVArgBase) -> String {
+    match arg.value_name() {
+        Some(value_name) => format!("<{}>", value_name),
+        None => format!("<{}>", arg.name().to_uppercase()),
+    }
+}
+
+fn pos_help_name(arg: &dyn PosArgBase) -> String {
+    if arg.variadic() {
+        format!("<{}>...", arg.name())
+    } else {
+        format!("<{}>", arg.name())
+    }
+}
+
+fn help_keys(long: Option<&str>, short_key: Option<char>) -> String {
+    match (long, short_key) {
+        (Some(long), Some(c)) => format!("--{}, -{}", long, c),
+        (Some(long), None) => format!("--{}", long),
+        (None, Some(c)) => format!("-{}", c),
+        (None, None) => String::new(),
+    }
+}
+
+fn push_help_aliases(desc: &mut String, aliases: &[String]) {
+    if !aliases.is_empty() {
+        let aliases: Vec<String> = aliases.iter().map(|alias| format!("--{}", alias)).collect();
+        desc.push_str(&format!(" [aliases: {}]", aliases.join(", ")));
+    }
+}
+
+// Lays out the pieces of Parser::format_help(). Each method returns whole
+// lines, newlines included.
+pub trait HelpFormatter {
+    // The program name and about text, if set
+    fn header(&self, name: Option<&str>, about: Option<&str>) -> String;
+    // Usage words, e.g. ["[options]", "<input>"]
+    fn usage(&self, words: &[String]) -> String;
+    // Starts a section: "arguments", "options", or a group's name
+    fn section(&self, title: &str) -> String;
+    // One argument; width is the widest keys in the help text. desc may span
+    // several lines.
+    fn row(&self, keys: &str, desc: &str, width: usize) -> String;
+}
+
+// What help() uses
+#[derive(Default)]
+pub struct DefaultHelpFormatter {
+    color: bool,
+}
+
+impl HelpFormatter for DefaultHelpFormatter {
+    fn header(&self, name: Option<&str>, about: Option<&str>) -> String {
+        let mut out = String::new();
+        if let Some(name) = name {
+            out.push_str(&format!("{}\n", paint(name, BOLD, self.color)));
+        }
+        if let Some(about) = about {
+            out.push_str(&format!("{}\n", about));
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+
+    fn usage(&self, words: &[String]) -> String {
+        let mut out = paint("usage:", BOLD, self.color);
+        for word in words {
+            out.push_str(&format!(" {}", word));
+        }
+        out.push('\n');
+        out
+    }
+
+    fn section(&self, title: &str) -> String {
+        format!("\n{}\n", paint(&format!("{}:", title), BOLD, self.color))
+    }
+
+    fn row(&self, keys: &str, desc: &str, width: usize) -> String {
+        // Padded before painting, so the escapes don't count toward the width
+        let keys = paint(&format!("{:width$}", keys, width = width), GREEN, self.color);
+        let mut lines = desc.lines();
+        let mut out = format!("    {}    {}\n", keys, lines.next().unwrap_or(""));
+        for line in lines {
+            out.push_str(&format!("    {:width$}    {}\n", "", line, width = width));
+        }
+        out
+    }
+}
+
+// s as a quoted JSON string
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+fn paint(s: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", style, s, RESET)
+    } else {
+        String::from(s)
+    }
+}
+
+// With the "color" feature, output is styled when it goes to a terminal,
+// unless NO_COLOR is set or overridden with set_color_choice()
+#[cfg(feature = "color")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto = 0,
+    Always = 1,
+    Never = 2,
+}
+
+#[cfg(feature = "color")]
+static COLOR_CHOICE: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+#[cfg(feature = "color")]
+pub fn set_color_choice(choice: ColorChoice) {
+    COLOR_CHOICE.store(choice as u8, Ordering::Relaxed);
+}
+
+#[cfg(feature = "color")]
+fn use_color(is_terminal: bool) -> bool {
+    let choice = match COLOR_CHOICE.load(Ordering::Relaxed) {
+        1 => ColorChoice::Always,
+        2 => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    };
+    color_enabled(choice, std::env::var_os("NO_COLOR").as_deref(), is_terminal)
+}
+
+// use_color() without the globals; no_color is the NO_COLOR variable
+#[cfg(feature = "color")]
+fn color_enabled(choice: ColorChoice, no_color: Option<&OsStr>, is_terminal: bool) -> bool {
+    if no_color.is_some_and(|v| !v.is_empty()) {
+        return false;
+    }
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => is_terminal,
+    }
+}
+
+#[cfg(not(feature = "color"))]
+fn use_color(_is_terminal: bool) -> bool { false }
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::new("first", Some('f'), "first argument"));
+
+        let args = vec!["", "-f", "42"];
+
+        parser.parse_iter(args).unwrap();
+
+        assert!(*parser.get::<i32>(kv).unwrap() == 42);
+    }
+
+    #[test]
+    fn str_constructors() {
+        let mut parser = Parser::new();
+        let command = parser.add_pos_arg(CommandArg::new("command", "what to do", vec!["run".to_string()]));
+        let files = parser.add_pos_arg(VarPosArg::<String>::new("files", "input files"));
+        let first = parser.add_kv_arg(KVArg::<i32>::new("first", Some('f'), "first argument"));
+        let tags = parser.add_kv_arg(MultiKVArg::<String>::new("tag", None, "tags"));
+        let shape = parser.add_kv_arg(ChoiceArg::new("shape", None, "shape", vec!["round".to_string()]));
+        let path = parser.add_kv_arg(PathArg::new("path", None, "a path"));
+        let timeout = parser.add_kv_arg(DurationArg::new("timeout", None, "how long"));
+        let point = parser.add_kv_arg(FixedArityArg::<i32>::new("point", None, "x y", 2));
+        let defines = parser.add_kv_arg(MapArg::new("define", None, "definitions"));
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose", "be loud", Some('v')));
+        let feature = parser.add_flag_arg(NegatableFlag::new("feature", "the feature", None));
+        let color = parser.add_flag_arg(OptionalValueFlag::new("color", "colorize", None));
+        let debug = parser.add_count_arg(CountArg::new("debug", "debug level", Some('d')));
+
+        parser.parse_iter(vec!["prog", "run", "a", "-f", "1", "--tag", "x", "--shape", "round", "--path", "p",
+            "--timeout", "30s", "--point", "1", "2", "--define", "k=v", "-v", "--no-feature", "--color", "-dd"]).unwrap();
+        assert_eq!(parser.arg(command).get(), Some("run"));
+        assert_eq!(parser.arg(files).get(), &["a".to_string()]);
+        assert_eq!(parser.get(first), Some(&1));
+        assert_eq!(parser.arg_mut(tags).vals(), vec!["x".to_string()]);
+        assert_eq!(parser.arg(shape).get(), Some("round"));
+        assert_eq!(parser.arg(path).get(), Some(Path::new("p")));
+        assert_eq!(parser.arg(timeout).get(), Some(Duration::from_secs(30)));
+        assert_eq!(parser.arg(point).get(), &[1, 2]);
+        assert_eq!(parser.arg(defines).map().get("k").map(String::as_str), Some("v"));
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.arg(feature).get(), Some(false));
+        assert!(parser.arg(color).found());
+        assert_eq!(parser.arg(debug).count(), 2);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let mut parser = Parser::new();
+
+        let args = vec!["".to_string(), "-x".to_string()];
+
+        assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::UnknownKey{key: "x".to_string(), suggestion: None}));
+    }
+
+    #[test]
+    fn single_dash_positional() {
+        let mut parser = Parser::new();
+        let pos = parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+
+        let args = vec!["prog".to_string(), "-".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(pos).val(), Some("-".to_string()));
+    }
+
+    #[test]
+    fn single_char_positional() {
+        let mut parser = Parser::new();
+        let pos = parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+
+        let args = vec!["prog".to_string(), "x".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(pos).val(), Some("x".to_string()));
+    }
+
+    #[test]
+    fn long_key_equals_value() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<String>::new("filter".to_string(), Some('f'), "filter expression".to_string()));
+
+        let args = vec!["prog".to_string(), "--filter=a=b".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(kv).val(), Some("a=b".to_string()));
+    }
+
+    #[test]
+    fn short_key_equals_value() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string()));
+        let pos = parser.add_pos_arg(PosArg::<String>::new("rest".to_string(), "rest".to_string()));
+
+        let args = vec!["prog".to_string(), "-f=42".to_string(), "next".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(kv).val(), Some(42));
+        assert_eq!(parser.arg_mut(pos).val(), Some("next".to_string()));
+    }
+
+    #[test]
+    fn bundled_short_flags() {
+        let mut parser = Parser::new();
+        let a = parser.add_flag_arg(FlagArg::new("all".to_string(), "a".to_string(), Some('a')));
+        let b = parser.add_flag_arg(FlagArg::new("brief".to_string(), "b".to_string(), Some('b')));
+        let c = parser.add_flag_arg(FlagArg::new("color".to_string(), "c".to_string(), Some('c')));
+
+        let args = vec!["prog".to_string(), "-abc".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert!(parser.arg(a).found());
+        assert!(parser.arg(b).found());
+        assert!(parser.arg(c).found());
+    }
+
+    #[test]
+    fn bundled_short_kv_value() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+
+        let args = vec!["prog".to_string(), "-n5".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(kv).val(), Some(5));
+    }
+
+    #[test]
+    fn kv_default() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::with_default("count".to_string(), Some('c'), "count".to_string(), 10));
+
+        let args = vec!["prog".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert!(!parser.arg(kv).found());
+        assert_eq!(parser.arg_mut(kv).val(), Some(10));
+    }
+
+    #[test]
+    fn missing_required_kv() {
+        let mut kv = KVArg::<String>::new("input".to_string(), Some('i'), "input file".to_string());
+        kv.set_required(true);
+        let mut parser = Parser::new();
+        parser.add_kv_arg(kv);
+
+        let args = vec!["prog".to_string()];
+
+        assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::MissingRequired(vec!["input".to_string()])));
+    }
+
+    #[test]
+    fn help_text() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+        parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string()));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        parser.add_flag_arg(FlagArg::new("all".to_string(), "everything".to_string(), None));
+
+        let help = parser.help();
+
+        assert!(help.starts_with("usage: [options] <input>\n"));
+        assert!(help.contains("    <input>                input file\n"));
+        assert!(help.contains("    --first, -f <FIRST>    first argument\n"));
+        assert!(help.contains("    --verbose, -v          be loud\n"));
+        let all_pos = help.find("--all").unwrap();
+        let first_pos = help.find("--first").unwrap();
+        let verbose_pos = help.find("--verbose").unwrap();
+        assert!(all_pos < first_pos && first_pos < verbose_pos);
+    }
+
+    #[test]
+    fn help_requested() {
+        let mut parser = Parser::new();
+
+        let args = vec!["prog".to_string(), "--help".to_string()];
+
+        assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::HelpRequested));
+    }
+
+    #[test]
+    fn user_defined_h() {
+        let mut parser = Parser::new();
+        let host = parser.add_kv_arg(KVArg::<String>::new("host".to_string(), Some('h'), "host name".to_string()));
+
+        let args = vec!["prog".to_string(), "-h".to_string(), "localhost".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(host).val(), Some("localhost".to_string()));
+    }
+
+    #[test]
+    fn multi_kv() {
+        let mut parser = Parser::new();
+        let include = parser.add_kv_arg(MultiKVArg::<String>::new("include".to_string(), Some('I'), "include path".to_string()));
+
+        let args: Vec<String> = vec!["prog", "-I", "a", "-I", "b", "-I", "c"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(include).vals(), vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn count_bundled() {
+        let mut parser = Parser::new();
+        let verbose = parser.add_count_arg(CountArg::new("verbose".to_string(), "verbosity".to_string(), Some('v')));
+
+        let args = vec!["prog".to_string(), "-vvv".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg(verbose).count(), 3);
+    }
+
+    #[test]
+    fn count_repeated() {
+        let mut parser = Parser::new();
+        let verbose = parser.add_count_arg(CountArg::new("verbose".to_string(), "verbosity".to_string(), Some('v')));
+
+        let args: Vec<String> = vec!["prog", "-v", "--verbose", "-v"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg(verbose).count(), 3);
+    }
+
+    #[test]
+    fn negative_number_positional() {
+        let mut parser = Parser::new();
+        parser.allow_negative_numbers(true);
+        let offset = parser.add_pos_arg(PosArg::<i32>::new("offset".to_string(), "offset".to_string()));
+
+        let args = vec!["prog".to_string(), "-5".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(offset).val(), Some(-5));
+    }
+
+    #[test]
+    fn negative_number_disallowed() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<i32>::new("offset".to_string(), "offset".to_string()));
+
+        let args = vec!["prog".to_string(), "-5".to_string()];
+
+        assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::UnknownKey{key: "5".to_string(), suggestion: None}));
+    }
+
+    #[test]
+    fn end_of_options() {
+        let mut parser = Parser::new();
+        let file = parser.add_pos_arg(PosArg::<String>::new("file".to_string(), "file".to_string()));
+
+        let args: Vec<String> = vec!["prog", "--", "-weird"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(file).val(), Some("-weird".to_string()));
+    }
+
+    #[test]
+    fn invalid_value() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("count".to_string(), Some('c'), "count".to_string()));
+
+        let args: Vec<String> = vec!["prog", "--count", "abc"]
+            .into_iter().map(String::from).collect();
+
+        match parser.parse_vec(args).map_err(|e| e.kind) {
+            Err(ParseError::InvalidValue{name, value, ..}) => {
+                assert_eq!(name, "count");
+                assert_eq!(value, "abc");
+            },
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn get_does_not_consume() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string()));
+
+        let args = vec!["prog".to_string(), "-f".to_string(), "42".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg(kv).get(), Some(&42));
+        assert_eq!(parser.arg(kv).get(), Some(&42));
+    }
+
+    #[test]
+    fn subcommands() {
+        let mut commit = Parser::new();
+        let message = commit.add_kv_arg(KVArg::<String>::new("message".to_string(), Some('m'), "commit message".to_string()));
+
+        let mut clone = Parser::new();
+        clone.add_pos_arg(PosArg::<String>::new("url".to_string(), "repo url".to_string()));
+
+        let mut parser = Parser::new();
+        parser.add_subcommand("commit", commit);
+        parser.add_subcommand("clone", clone);
+
+        let args: Vec<String> = vec!["prog", "commit", "-m", "msg"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.matched_subcommand(), Some("commit"));
+        assert_eq!(parser.subcommand("commit").unwrap().get(message), Some(&"msg".to_string()));
+    }
+
+    #[test]
+    fn choice_arg() {
+        let choices = vec!["auto".to_string(), "always".to_string(), "never".to_string()];
+        let mut parser = Parser::new();
+        parser.add_kv_arg(ChoiceArg::new("color".to_string(), None, "when to color".to_string(), choices.clone()));
+
+        let args: Vec<String> = vec!["prog", "--color", "purple"]
+            .into_iter().map(String::from).collect();
+
+        assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::InvalidChoice{
+            name: "color".to_string(),
+            value: "purple".to_string(),
+            allowed: choices.clone(),
+        }));
+        assert!(parser.help().contains("when to color [possible values: auto, always, never]"));
+
+        let mut parser = Parser::new();
+        let color = parser.add_kv_arg(ChoiceArg::new("color".to_string(), None, "when to color".to_string(), choices));
+
+        let args: Vec<String> = vec!["prog", "--color", "auto"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg(color).get(), Some("auto"));
+    }
+
+    #[test]
+    fn env_fallback() {
+        std::env::set_var("ARGS_TEST_ENV_FALLBACK", "7");
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::with_env("jobs".to_string(), Some('j'), "jobs".to_string(), "ARGS_TEST_ENV_FALLBACK"));
+
+        parser.parse_vec(vec!["prog".to_string()]).unwrap();
+
+        assert_eq!(parser.get(kv), Some(&7));
+
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::with_env("jobs".to_string(), Some('j'), "jobs".to_string(), "ARGS_TEST_ENV_FALLBACK"));
+
+        let args = vec!["prog".to_string(), "-j".to_string(), "3".to_string()];
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.get(kv), Some(&3));
+    }
+
+    #[test]
+    fn aliases() {
+        for key in &["--color", "--colour"] {
+            let mut color = KVArg::<String>::new("color".to_string(), None, "when to color".to_string());
+            color.add_alias("colour");
+            let mut parser = Parser::new();
+            let color = parser.add_kv_arg(color);
+
+            let args = vec!["prog".to_string(), key.to_string(), "auto".to_string()];
+
+            parser.parse_vec(args).unwrap();
+
+            assert_eq!(parser.get(color), Some(&"auto".to_string()));
+            assert!(parser.help().contains("when to color [aliases: --colour]"));
+        }
+
+        let mut quiet = FlagArg::new("quiet".to_string(), "say less".to_string(), None);
+        quiet.add_alias("silent");
+        let mut parser = Parser::new();
+        let quiet = parser.add_flag_arg(quiet);
+
+        parser.parse_vec(vec!["prog".to_string(), "--silent".to_string()]).unwrap();
+
+        assert!(parser.arg(quiet).found());
+    }
+
+    #[test]
+    fn negatable_flag() {
+        for (argv, expected) in [(vec!["prog", "--verbose"], Some(true)),
+                                 (vec!["prog", "--no-verbose"], Some(false)),
+                                 (vec!["prog"], None)] {
+            let mut parser = Parser::new();
+            let verbose = parser.add_flag_arg(NegatableFlag::new("verbose".to_string(), "be loud".to_string(), None));
+
+            parser.parse_vec(argv.into_iter().map(String::from).collect()).unwrap();
+
+            assert_eq!(parser.arg(verbose).get(), expected);
+            assert!(parser.help().contains("--verbose / --no-verbose    be loud"));
+        }
+    }
+
+    #[test]
+    fn version_requested() {
+        let mut parser = Parser::new();
+        parser.version("1.2.3");
+
+        let args = vec!["prog".to_string(), "--version".to_string()];
+
+        assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::VersionRequested("1.2.3".to_string())));
+        assert!(parser.help().contains("--version, -V    print version"));
+    }
+
+    #[test]
+    fn trailing_key_missing_value() {
+        for (key, expected) in [("--output", "output"), ("-o", "o")] {
+            let mut parser = Parser::new();
+            parser.add_kv_arg(KVArg::<String>::new("output".to_string(), Some('o'), "output file".to_string()));
+
+            let args = vec!["prog".to_string(), key.to_string()];
+
+            assert_eq!(parser.parse_vec(args).map_err(|e| e.kind), Err(ParseError::MissingValue(expected.to_string())));
+        }
+    }
+
+    #[test]
+    fn delimited_multi_kv() {
+        let mut features = MultiKVArg::<String>::new("features".to_string(), None, "features".to_string());
+        features.set_delimiter(',');
+        let mut parser = Parser::new();
+        let features = parser.add_kv_arg(features);
+
+        let args: Vec<String> = vec!["prog", "--features", "a,b,c"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(features).vals().len(), 3);
+
+        let args: Vec<String> = vec!["prog", "--features", "a,b", "--features", "c"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.arg_mut(features).vals(), vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn abbreviations() {
+        let mut parser = Parser::new();
+        parser.allow_abbreviations(true);
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), None));
+        parser.add_flag_arg(FlagArg::new("version-check".to_string(), "check version".to_string(), None));
+
+        parser.parse_vec(vec!["prog".to_string(), "--verb".to_string()]).unwrap();
+
+        assert!(parser.arg(verbose).found());
+
+        let mut parser = Parser::new();
+        parser.allow_abbreviations(true);
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), None));
+        parser.add_flag_arg(FlagArg::new("version-check".to_string(), "check version".to_string(), None));
+
+        assert_eq!(parser.parse_vec(vec!["prog".to_string(), "--ver".to_string()]).map_err(|e| e.kind), Err(ParseError::AmbiguousKey{
+            input: "ver".to_string(),
+            candidates: vec!["verbose".to_string(), "version-check".to_string()],
+        }));
+    }
+
+    #[test]
+    fn unknown_key_suggestion() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), None));
+
+        assert_eq!(parser.parse_vec(vec!["prog".to_string(), "--verbse".to_string()]).map_err(|e| e.kind), Err(ParseError::UnknownKey{
+            key: "verbse".to_string(),
+            suggestion: Some("verbose".to_string()),
+        }));
+        assert_eq!(parser.parse_vec(vec!["prog".to_string(), "--quiet".to_string()]).map_err(|e| e.kind), Err(ParseError::UnknownKey{
+            key: "quiet".to_string(),
+            suggestion: None,
+        }));
+    }
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("abc", ""), 3);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("verbse", "verbose"), 1);
+    }
+
+    #[test]
+    fn trailing_args() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("force".to_string(), "force".to_string(), Some('f')));
+
+        let args: Vec<String> = vec!["prog", "-f", "--", "x", "y"]
+            .into_iter().map(String::from).collect();
+
+        parser.parse_vec(args).unwrap();
+
+        assert_eq!(parser.trailing(), &["x".to_string(), "y".to_string()]);
+    }
+
+    #[test]
+    fn parse_str_slice() {
+        let mut parser = Parser::new();
+        let kv = parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string()));
+
+        let argv: &[&str] = &["prog", "-f", "42"];
+        parser.parse_iter(argv).unwrap();
+
+        assert_eq!(parser.get(kv), Some(&42));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_values() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = OsStr::from_bytes(b"caf\xe9.txt");
+        let mut parser = Parser::new();
+        let input = parser.add_pos_arg(PosArg::<PathBuf>::new("input".to_string(), "input file".to_string()));
+        let count = parser.add_kv_arg(KVArg::<i32>::new("count".to_string(), Some('c'), "count".to_string()));
+
+        parser.parse_os(vec![OsString::from("prog"), path.to_os_string(), OsString::from("-c"), OsString::from("3")]).unwrap();
+
+        assert_eq!(parser.arg(input).get(), Some(&PathBuf::from(path)));
+        assert_eq!(parser.get(count), Some(&3));
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("count".to_string(), Some('c'), "count".to_string()));
+
+        let key = OsStr::from_bytes(b"--caf\xe9");
+        assert_eq!(parser.parse_os(vec![OsString::from("prog"), key.to_os_string()]),
+            Err(ParseError::InvalidUtf8("--caf\u{fffd}".to_string())));
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("count".to_string(), Some('c'), "count".to_string()));
+
+        match parser.parse_os(vec![OsString::from("prog"), OsString::from("-c"), path.to_os_string()]) {
+            Err(ParseError::InvalidValue{name, ..}) => assert_eq!(name, "count"),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn kv_builder() {
+        let mut parser = Parser::new();
+        let count = parser.add_kv_arg(KVArg::<i32>::builder("count")
+            .short('c')
+            .default(1)
+            .required(false)
+            .desc("how many")
+            .build());
+        let name = parser.add_kv_arg(KVArg::<String>::builder("name").alias("nom").required(true).build());
+
+        assert!(parser.help().contains("--count, -c <COUNT>    how many"));
+
+        parser.parse_iter(vec!["prog", "--nom", "x"]).unwrap();
+        assert_eq!(parser.get(count), Some(&1));
+        assert_eq!(parser.get(name), Some(&String::from("x")));
+        assert!(!parser.arg(count).found());
+    }
+
+    #[test]
+    fn mutually_exclusive() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("quiet".to_string(), "say less".to_string(), Some('q')));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "say more".to_string(), Some('v')));
+        parser.add_mutually_exclusive(&["quiet", "verbose"]);
+
+        assert_eq!(parser.parse_iter(vec!["prog", "-v", "--quiet"]),
+            Err(ParseError::Conflict{a: "quiet".to_string(), b: "verbose".to_string()}));
+
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("quiet".to_string(), "say less".to_string(), Some('q')));
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "say more".to_string(), Some('v')));
+        parser.add_mutually_exclusive(&["quiet", "verbose"]);
+
+        parser.parse_iter(vec!["prog", "-v"]).unwrap();
+        assert!(parser.arg(verbose).found());
+    }
+
+    #[test]
+    fn requires() {
+        let make_parser = || {
+            let mut parser = Parser::new();
+            parser.add_kv_arg(KVArg::<String>::new("cert".to_string(), None, "certificate".to_string()));
+            parser.add_kv_arg(KVArg::<String>::new("key".to_string(), None, "private key".to_string()));
+            parser.add_requires("cert", "key");
+            parser
+        };
+
+        assert_eq!(make_parser().parse_iter(vec!["prog", "--cert", "c.pem"]),
+            Err(ParseError::MissingDependency{arg: "cert".to_string(), requires: "key".to_string()}));
+        assert_eq!(make_parser().parse_iter(vec!["prog", "--cert", "c.pem", "--key", "k.pem"]), Ok(()));
+        assert_eq!(make_parser().parse_iter(vec!["prog", "--key", "k.pem"]), Ok(()));
+    }
+
+    #[test]
+    fn hidden_args() {
+        let mut parser = Parser::new();
+        let mut debug = FlagArg::new("debug-internals".to_string(), "dump state".to_string(), None);
+        debug.set_hidden(true);
+        let debug = parser.add_flag_arg(debug);
+        parser.add_kv_arg(KVArg::<i32>::builder("secret").hidden(true).build());
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+
+        let help = parser.help();
+        assert!(!help.contains("debug-internals"));
+        assert!(!help.contains("secret"));
+        assert!(help.contains("--verbose"));
+
+        parser.parse_iter(vec!["prog", "--debug-internals", "--secret", "1"]).unwrap();
+        assert!(parser.arg(debug).found());
+    }
+
+    #[test]
+    fn value_name() {
+        let mut parser = Parser::new();
+        let mut output = KVArg::<String>::new("output".to_string(), Some('o'), "where to write".to_string());
+        output.set_value_name("FILE");
+        parser.add_kv_arg(output);
+
+        assert!(parser.help().contains("    --output, -o <FILE>    where to write\n"));
+    }
+
+    #[test]
+    fn variadic_positional() {
+        let mut parser = Parser::new();
+        let cmd = parser.add_pos_arg(PosArg::<String>::new("cmd".to_string(), "command".to_string()));
+        let files = parser.add_pos_arg(VarPosArg::<String>::new("files".to_string(), "inputs".to_string()));
+
+        assert!(parser.help().starts_with("usage: [options] <cmd> <files>...\n"));
+
+        parser.parse_iter(vec!["prog", "a", "b", "c"]).unwrap();
+        assert_eq!(parser.arg(cmd).get(), Some(&"a".to_string()));
+        assert_eq!(parser.arg_mut(files).vals(), vec!["b".to_string(), "c".to_string()]);
+
+        // Both are optional unless set_required()
+        let mut parser = Parser::new();
+        let cmd = parser.add_pos_arg(PosArg::<String>::new("cmd".to_string(), "command".to_string()));
+        let files = parser.add_pos_arg(VarPosArg::<String>::new("files".to_string(), "inputs".to_string()));
+        parser.parse_iter(vec!["prog"]).unwrap();
+        assert!(!parser.arg(cmd).found());
+        assert!(parser.arg(files).get().is_empty());
+    }
+
+    #[test]
+    fn validator() {
+        let make_parser = || {
+            let mut parser = Parser::new();
+            let port = parser.add_kv_arg(KVArg::<u32>::builder("port")
+                .validator(|port| if (1..=65535).contains(port) {
+                    Ok(())
+                } else {
+                    Err(format!("{} is not a valid port", port))
+                })
+                .build());
+            (parser, port)
+        };
+
+        let (mut parser, _) = make_parser();
+        assert_eq!(parser.parse_iter(vec!["prog", "--port", "70000"]),
+            Err(ParseError::Validation{name: "port".to_string(), msg: "70000 is not a valid port".to_string()}));
+
+        let (mut parser, port) = make_parser();
+        parser.parse_iter(vec!["prog", "--port", "8080"]).unwrap();
+        assert_eq!(parser.get(port), Some(&8080));
+    }
+
+    #[test]
+    fn attached_short_values() {
+        let make_parser = || {
+            let mut parser = Parser::new();
+            let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+            let num = parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+            let file = parser.add_kv_arg(KVArg::<String>::new("file".to_string(), Some('f'), "file".to_string()));
+            (parser, verbose, num, file)
+        };
+
+        let (mut parser, verbose, num, _) = make_parser();
+        parser.parse_iter(vec!["prog", "-vn5"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(num), Some(&5));
+
+        let (mut parser, verbose, _, file) = make_parser();
+        parser.parse_iter(vec!["prog", "-vf", "out"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(file), Some(&"out".to_string()));
+
+        // Everything after a kv short key is its value, even if it looks like a key
+        let (mut parser, _, _, file) = make_parser();
+        parser.parse_iter(vec!["prog", "-fv"]).unwrap();
+        assert_eq!(parser.get(file), Some(&"v".to_string()));
+
+        let (mut parser, _, _, _) = make_parser();
+        assert!(matches!(parser.parse_iter(vec!["prog", "-nf"]), Err(ParseError::InvalidValue{..})));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let mut parser = Parser::new();
+        parser.case_insensitive(true);
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let num = parser.add_kv_arg(KVArg::<i32>::new("Num".to_string(), Some('N'), "number".to_string()));
+
+        parser.parse_iter(vec!["prog", "--VERBOSE", "-n", "3"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(num), Some(&3));
+
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        assert!(matches!(parser.parse_iter(vec!["prog", "--VERBOSE"]), Err(ParseError::UnknownKey{..})));
+    }
+
+    #[test]
+    fn case_insensitive_builtin_keys() {
+        let mut parser = Parser::new();
+        parser.case_insensitive(true);
+        parser.version("1.0");
+        parser.add_kv_arg(KVArg::<String>::new("output", Some('o'), ""));
+
+        assert_eq!(parser.parse_iter(vec!["prog", "-v"]), Err(ParseError::VersionRequested("1.0".to_string())));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "-H"]), Err(ParseError::HelpRequested));
+        // Known keys, so not taken as a value
+        for key in ["-v", "-V", "-H", "--HELP"] {
+            parser.reset();
+            assert_eq!(parser.parse_iter(vec!["prog", "--output", key]), Err(ParseError::MissingValue("output".to_string())));
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mut parser = Parser::new();
+        let input = parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+        let num = parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let count = parser.add_count_arg(CountArg::new("level".to_string(), "level".to_string(), Some('l')));
+
+        parser.parse_iter(vec!["prog", "a", "-n", "1", "-v", "-ll", "--", "x", "y"]).unwrap();
+        assert_eq!(parser.trailing(), &["x".to_string(), "y".to_string()]);
+
+        parser.reset();
+        assert!(!parser.arg(input).found() && !parser.arg(num).found() && !parser.arg(verbose).found());
+        assert_eq!(parser.arg(count).count(), 0);
+        assert!(parser.trailing().is_empty());
+
+        parser.parse_iter(vec!["prog", "b", "-n", "2", "-v"]).unwrap();
+        assert_eq!(parser.arg(input).get(), Some(&"b".to_string()));
+        assert_eq!(parser.get(num), Some(&2));
+        assert!(parser.arg(verbose).found());
+    }
+
+    #[test]
+    fn missing_positionals() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+        parser.add_pos_arg(PosArg::<String>::new("output".to_string(), "output file".to_string()));
+
+        parser.parse_iter(vec!["prog", "in.txt"]).unwrap();
+        assert_eq!(parser.missing_positionals(), vec!["output"]);
+    }
+
+    #[test]
+    fn load_defaults() {
+        let path = std::env::temp_dir().join(format!("args_load_defaults_{}.conf", std::process::id()));
+        std::fs::write(&path, "# defaults\nnum = 5\n\nname = from config\nverbose = true\n").unwrap();
+
+        let mut parser = Parser::new();
+        let num = parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+        let name = parser.add_kv_arg(KVArg::<String>::new("name".to_string(), None, "name".to_string()));
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        parser.load_defaults(&path).unwrap();
+
+        // The command line wins
+        parser.parse_iter(vec!["prog", "-n", "7"]).unwrap();
+        assert_eq!(parser.get(num), Some(&7));
+        assert_eq!(parser.get(name), Some(&"from config".to_string()));
+        assert!(parser.arg(verbose).found());
+
+        std::fs::write(&path, "num = 5\nnum 6\n").unwrap();
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+        assert_eq!(parser.load_defaults(&path), Err(ParseError::Config{
+            path: path.display().to_string(),
+            line: Some(2),
+            msg: "expected `key = value`, got `num 6`".to_string(),
+        }));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn radix_int() {
+        for (input, expected) in [("0x1F", 31), ("0o17", 15), ("0b101", 5), ("42", 42), ("-0x10", -16)] {
+            let mut parser = Parser::new();
+            let num = parser.add_kv_arg(KVArg::<RadixInt<i64>>::new("num".to_string(), Some('n'), "number".to_string()));
+            parser.parse_iter(vec!["prog", "-n", input]).unwrap();
+            assert_eq!(parser.get(num), Some(&RadixInt(expected)));
+        }
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<RadixInt<u8>>::new("num".to_string(), Some('n'), "number".to_string()));
+        assert!(matches!(parser.parse_iter(vec!["prog", "-n", "0x100"]), Err(ParseError::InvalidValue{..})));
+
+        for input in ["0x-5", "0x+5", "-0x-5", "0b-1", "+-5", "--5"] {
+            assert!(input.parse::<RadixInt<i32>>().is_err(), "{}", input);
+        }
+        assert_eq!("+0x5".parse::<RadixInt<i32>>(), Ok(RadixInt(5)));
+    }
+
+    #[test]
+    fn path_arg() {
+        let file = std::env::temp_dir().join(format!("args_path_arg_{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let missing = std::env::temp_dir().join("args_path_arg_does_not_exist");
+        let file_str = file.to_str().unwrap();
+        let missing_str = missing.to_str().unwrap();
+
+        let make_parser = |check| {
+            let mut parser = Parser::new();
+            let mut path = PathArg::new("path".to_string(), Some('p'), "a path".to_string());
+            path.set_check(check);
+            let path = parser.add_kv_arg(path);
+            (parser, path)
+        };
+
+        let (mut parser, path) = make_parser(PathCheck::File);
+        parser.parse_iter(vec!["prog", "-p", file_str]).unwrap();
+        assert_eq!(parser.arg(path).get(), Some(file.as_path()));
+
+        let (mut parser, _) = make_parser(PathCheck::Exists);
+        assert_eq!(parser.parse_iter(vec!["prog", "-p", missing_str]),
+            Err(ParseError::PathNotFound{name: "path".to_string(), path: missing_str.to_string()}));
+
+        let (mut parser, _) = make_parser(PathCheck::Dir);
+        assert_eq!(parser.parse_iter(vec!["prog", "-p", file_str]),
+            Err(ParseError::NotADirectory{name: "path".to_string(), path: file_str.to_string()}));
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn duration_arg() {
+        let make_parser = || {
+            let mut parser = Parser::new();
+            let timeout = parser.add_kv_arg(DurationArg::new("timeout".to_string(), Some('t'), "how long".to_string()));
+            (parser, timeout)
+        };
+
+        let (mut parser, timeout) = make_parser();
+        parser.parse_iter(vec!["prog", "--timeout", "30s"]).unwrap();
+        assert_eq!(parser.arg(timeout).get(), Some(Duration::from_secs(30)));
+
+        let (mut parser, timeout) = make_parser();
+        parser.parse_iter(vec!["prog", "-t", "1500ms"]).unwrap();
+        assert_eq!(parser.arg(timeout).get(), Some(Duration::from_millis(1500)));
+
+        let (mut parser, _) = make_parser();
+        assert!(matches!(parser.parse_iter(vec!["prog", "-t", "abc"]), Err(ParseError::InvalidValue{..})));
+    }
+
+    #[test]
+    fn error_index() {
+        let mut parser = Parser::new();
+        let mut input = PosArg::<String>::new("input".to_string(), "input file".to_string());
+        input.set_required(true);
+        parser.add_pos_arg(input);
+        parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+
+        assert!(parser.parse_iter(vec!["prog", "-n", "abc", "in"]).is_err());
+        assert_eq!(parser.error_index(), Some(2));
+
+        parser.reset();
+        assert!(parser.parse_iter(vec!["prog", "in", "-v", "--bogus"]).is_err());
+        assert_eq!(parser.error_index(), Some(3));
+
+        parser.reset();
+        assert!(parser.parse_iter(vec!["prog", "-v"]).is_err());
+        assert_eq!(parser.error_index(), None);
+
+        let mut sub_parser = Parser::new();
+        sub_parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "number".to_string()));
+        let mut parser = Parser::new();
+        parser.add_subcommand("run", sub_parser);
+        assert!(parser.parse_iter(vec!["prog", "run", "-n", "abc"]).is_err());
+        assert_eq!(parser.error_index(), Some(3));
+
+        // The index also travels with the error
+        parser.reset();
+        let err = parser.parse_located(vec!["prog", "run", "-n", "abc"]).unwrap_err();
+        assert!(matches!(err.kind, ParseError::InvalidValue{..}));
+        assert_eq!(err.index, Some(3));
+        parser.reset();
+        let argv: Vec<String> = vec!["prog", "run", "-x"].into_iter().map(String::from).collect();
+        assert_eq!(parser.parse_vec(argv), Err(LocatedError{
+            kind: ParseError::UnknownKey{key: "x".to_string(), suggestion: None},
+            index: Some(2),
+        }));
+    }
+
+    #[test]
+    fn completions() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string()));
+        parser.add_flag_arg(NegatableFlag::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        parser.add_count_arg(CountArg::new("level".to_string(), "level".to_string(), None));
+        parser.add_kv_arg(KVArg::<i32>::builder("secret").hidden(true).build());
+        parser.add_subcommand("build", Parser::new());
+
+        let bash = parser.completions_for(Shell::Bash, "my-prog");
+        assert!(bash.contains("complete -o default -F _my_prog my-prog\n"));
+        for key in ["--first", "-f", "--verbose", "--no-verbose", "-v", "--level", "--help", "-h"] {
+            assert!(bash.contains(&format!("{} ", key)) || bash.contains(&format!("{}\"", key)), "{} missing", key);
+        }
+        assert!(!bash.contains("--secret"));
+        assert!(bash.contains("compgen -W \"build\""));
+
+        let zsh = parser.completions_for(Shell::Zsh, "my-prog");
+        assert!(zsh.starts_with("#compdef my-prog\n"));
+        assert!(zsh.contains("--first"));
+
+        // By default, the name from name() or argv[0]
+        parser.name("tool");
+        assert_eq!(parser.completions(Shell::Zsh), parser.completions_for(Shell::Zsh, "tool"));
+        let mut parser = Parser::new();
+        parser.parse_iter(vec!["/usr/bin/other-tool"]).unwrap();
+        assert!(parser.completions(Shell::Bash).ends_with("complete -o default -F _other_tool other-tool\n"));
+    }
+
+    #[test]
+    fn global_args() {
+        let make_parser = || {
+            let mut sub_parser = Parser::new();
+            sub_parser.add_flag_arg(FlagArg::new("force".to_string(), "force".to_string(), Some('f')));
+            let mut parser = Parser::new();
+            let verbose = parser.add_global_flag(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+            let jobs = parser.add_global_kv(KVArg::<u32>::new("jobs".to_string(), Some('j'), "jobs".to_string()));
+            parser.add_subcommand("sub", sub_parser);
+            (parser, verbose, jobs)
+        };
+
+        let (mut parser, verbose, _) = make_parser();
+        parser.parse_iter(vec!["prog", "--verbose", "sub"]).unwrap();
+        assert!(parser.arg(verbose).found());
+
+        let (mut parser, verbose, jobs) = make_parser();
+        parser.parse_iter(vec!["prog", "sub", "-f", "--verbose", "-j", "4"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(jobs), Some(&4));
+        assert_eq!(parser.matched_subcommand(), Some("sub"));
+
+        // Errors from the subcommand still point at the right token
+        let (mut parser, _, _) = make_parser();
+        assert!(parser.parse_iter(vec!["prog", "sub", "-v", "--bogus"]).is_err());
+        assert_eq!(parser.error_index(), Some(3));
+    }
+
+    #[test]
+    fn override_mode() {
+        let mut parser = Parser::new();
+        let out = parser.add_kv_arg(KVArg::<String>::builder("out").short('o').override_mode(true).build());
+        parser.parse_iter(vec!["prog", "--out", "a", "-o", "b"]).unwrap();
+        assert_eq!(parser.get(out), Some(&"b".to_string()));
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::builder("out").short('o').build());
+        assert_eq!(parser.parse_iter(vec!["prog", "--out", "a", "--out", "b"]),
+            Err(ParseError::DuplicateArg("out".to_string())));
+    }
+
+    #[test]
+    fn value_of() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+        parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first argument".to_string()));
+        parser.add_kv_arg(KVArg::<i32>::with_default("second".to_string(), None, "second".to_string(), 2));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+
+        parser.parse_iter(vec!["prog", "-f", "42", "-v", "in.txt"]).unwrap();
+        assert_eq!(parser.value_of::<i32>("first"), Some(42));
+        assert_eq!(parser.value_of::<String>("input"), Some("in.txt".to_string()));
+        assert_eq!(parser.value_of::<i32>("second"), None);
+        assert_eq!(parser.value_of::<i32>("input"), None);
+        assert!(parser.is_present("verbose"));
+        assert!(parser.is_present("first"));
+        assert!(!parser.is_present("second"));
+    }
+
+    #[test]
+    fn colored_output() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+
+        assert!(parser.render_help(true).contains("\x1b[32m--verbose, -v\x1b[0m    be loud\n"));
+        assert!(!parser.render_help(false).contains('\x1b'));
+
+        let err = ParseError::TooManyPositional;
+        assert_eq!(err.paint(true), "\x1b[1;31merror:\x1b[0m too many positional arguments");
+        assert_eq!(err.paint(false), format!("error: {}", err));
+        assert!(!err.to_string().contains('\x1b'));
+        assert!(parser.render_error_with_usage(&err, true).starts_with("\x1b[1;31merror:"));
+    }
+
+    #[cfg(feature = "color")]
+    #[test]
+    fn color_choice() {
+        let no_color = Some(OsStr::new("1"));
+        assert!(color_enabled(ColorChoice::Auto, None, true));
+        assert!(!color_enabled(ColorChoice::Auto, None, false));
+        assert!(color_enabled(ColorChoice::Always, None, false));
+        assert!(!color_enabled(ColorChoice::Never, None, true));
+        assert!(!color_enabled(ColorChoice::Always, no_color, true));
+        // An empty NO_COLOR doesn't count
+        assert!(color_enabled(ColorChoice::Auto, Some(OsStr::new("")), true));
+    }
+
+    #[test]
+    fn error_display() {
+        let err = ParseError::UnknownKey{key: "foo".to_string(), suggestion: None};
+        assert_eq!(err.to_string(), "unknown option `--foo`");
+
+        let err = ParseError::UnknownKey{key: "verbse".to_string(), suggestion: Some("verbose".to_string())};
+        assert_eq!(err.to_string(), "unknown option `--verbse`; did you mean `--verbose`?");
+
+        assert_eq!(ParseError::MissingValue("n".to_string()).to_string(), "`-n` needs a value");
+
+        let err: Box<dyn std::error::Error> = Box::new(ParseError::TooManyPositional);
+        assert_eq!(err.to_string(), "too many positional arguments");
+
+        // The prefix is only added when printed as the parser would
+        let err = ParseError::Multiple(vec![ParseError::TooManyPositional, ParseError::MissingValue("n".to_string())]);
+        assert_eq!(err.to_string(), "too many positional arguments\n`-n` needs a value");
+        assert_eq!(err.paint(false), "error: too many positional arguments\nerror: `-n` needs a value");
+    }
+
+    #[test]
+    fn bool_kv() {
+        let spellings = [("true", true), ("TRUE", true), ("1", true), ("yes", true), ("Yes", true),
+            ("false", false), ("False", false), ("0", false), ("no", false), ("NO", false)];
+        for (input, expected) in spellings {
+            let mut parser = Parser::new();
+            let color = parser.add_kv_arg(KVArg::<bool>::new("color".to_string(), None, "use color".to_string()));
+            parser.parse_iter(vec!["prog", "--color", input]).unwrap();
+            assert_eq!(parser.get(color), Some(&expected), "{}", input);
+        }
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<bool>::new("color".to_string(), None, "use color".to_string()));
+        assert_eq!(parser.parse_iter(vec!["prog", "--color", "maybe"]), Err(ParseError::InvalidValue{
+            name: "color".to_string(),
+            value: "maybe".to_string(),
+            msg: "expected true/false, 1/0 or yes/no, got `maybe`".to_string(),
+        }));
+    }
+
+    #[test]
+    fn help_header() {
+        let mut parser = Parser::new();
+        parser.name("frob");
+        parser.about("Frobnicates widgets");
+        assert!(parser.help().starts_with("frob\nFrobnicates widgets\n\nusage: [options]\n"));
+
+        // Otherwise the name comes from argv[0]
+        let mut parser = Parser::new();
+        parser.parse_iter(vec!["/usr/bin/widget"]).unwrap();
+        assert!(parser.help().starts_with("widget\n\nusage:"));
+    }
+
+    #[test]
+    fn missing_positional() {
+        let mut input = PosArg::<String>::new("input".to_string(), "input file".to_string());
+        input.set_required(true);
+        let mut files = VarPosArg::<String>::new("files".to_string(), "more files".to_string());
+        files.set_required(true);
+        let mut parser = Parser::new();
+        parser.add_pos_arg(input);
+        parser.add_pos_arg(files);
+
+        assert_eq!(parser.parse_iter(vec!["prog"]), Err(ParseError::MissingPositional("input".to_string())));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "a"]), Err(ParseError::MissingPositional("files".to_string())));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "a", "b"]), Ok(()));
+    }
+
+    #[test]
+    fn allow_hyphen_values() {
+        let make_parser = |allow| {
+            let mut parser = Parser::new();
+            let pattern = parser.add_kv_arg(KVArg::<String>::builder("pattern").short('p').allow_hyphen_values(allow).build());
+            let x = parser.add_flag_arg(FlagArg::new("extended".to_string(), "extended".to_string(), Some('x')));
+            (parser, pattern, x)
+        };
+
+        let (mut parser, pattern, x) = make_parser(true);
+        parser.parse_iter(vec!["prog", "--pattern", "-x"]).unwrap();
+        assert_eq!(parser.get(pattern), Some(&"-x".to_string()));
+        assert!(!parser.arg(x).found());
+
+        let (mut parser, _, _) = make_parser(false);
+        assert_eq!(parser.parse_iter(vec!["prog", "--pattern", "-x"]), Err(ParseError::MissingValue("pattern".to_string())));
+        let (mut parser, _, _) = make_parser(false);
+        assert_eq!(parser.parse_iter(vec!["prog", "-p", "--help"]), Err(ParseError::MissingValue("p".to_string())));
+
+        // Dashed values that aren't keys are fine either way
+        let (mut parser, pattern, _) = make_parser(false);
+        parser.parse_iter(vec!["prog", "-p", "-foo"]).unwrap();
+        assert_eq!(parser.get(pattern), Some(&"-foo".to_string()));
+    }
+
+    #[test]
+    fn map_arg() {
+        let mut parser = Parser::new();
+        let defines = parser.add_kv_arg(MapArg::new("define".to_string(), Some('D'), "definitions".to_string()));
+        assert!(parser.help().contains("--define, -D <KEY=VALUE>"));
+
+        parser.parse_iter(vec!["prog", "--define", "a=1", "-Db=x=y"]).unwrap();
+        let expected: BTreeMap<String, String> = vec![("a".to_string(), "1".to_string()), ("b".to_string(), "x=y".to_string())]
+            .into_iter().collect();
+        assert_eq!(parser.arg(defines).map(), &expected);
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(MapArg::new("define".to_string(), Some('D'), "definitions".to_string()));
+        assert!(matches!(parser.parse_iter(vec!["prog", "-D", "novalue"]), Err(ParseError::InvalidValue{..})));
+    }
+
+    #[test]
+    fn short_only() {
+        let mut parser = Parser::new();
+        let x = parser.add_flag_arg(FlagArg::short_only('x', "extract".to_string()));
+        let n = parser.add_kv_arg(KVArg::<i32>::short_only('n', "count".to_string()));
+        let help = parser.help();
+        assert!(help.contains("  -x "));
+        assert!(help.contains("  -n <N> "));
+
+        parser.parse_iter(vec!["prog", "-x", "-n", "3"]).unwrap();
+        assert!(parser.arg(x).found());
+        assert_eq!(parser.get(n), Some(&3));
+    }
+
+    #[test]
+    fn help_order() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("zeta".to_string(), None, "z".to_string()));
+        parser.add_flag_arg(FlagArg::new("alpha".to_string(), "a".to_string(), None));
+        parser.add_count_arg(CountArg::new("mid".to_string(), "m".to_string(), None));
+
+        let option_lines = |parser: &Parser| -> Vec<String> {
+            parser.help().lines()
+                .skip_while(|line| *line != "options:")
+                .skip(1)
+                .map(|line| String::from(line.split_whitespace().next().unwrap()))
+                .collect()
+        };
+        assert_eq!(option_lines(&parser), vec!["--alpha", "--help,", "--mid", "--zeta"]);
+
+        parser.help_order(Order::Insertion);
+        assert_eq!(option_lines(&parser), vec!["--zeta", "--alpha", "--mid", "--help,"]);
+    }
+
+    #[test]
+    fn custom_help_formatter() {
+        struct Quoted;
+        impl HelpFormatter for Quoted {
+            fn header(&self, name: Option<&str>, _about: Option<&str>) -> String {
+                name.map(|name| format!("> # {}\n", name)).unwrap_or_default()
+            }
+            fn usage(&self, words: &[String]) -> String { format!("> usage: {}\n", words.join(" ")) }
+            fn section(&self, title: &str) -> String { format!("> ## {}\n", title) }
+            fn row(&self, keys: &str, desc: &str, _width: usize) -> String { format!("> {}: {}\n", keys, desc) }
+        }
+
+        let mut parser = Parser::new();
+        parser.name("prog");
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+
+        let help = parser.format_help(&Quoted);
+        assert!(help.lines().all(|line| line.starts_with('>')));
+        assert!(help.contains("> usage: [options] <input>\n"));
+        assert!(help.contains("> --verbose, -v: be loud\n"));
+
+        assert_eq!(parser.format_help(&DefaultHelpFormatter::default()), parser.help());
+    }
+
+    #[test]
+    fn range() {
+        let mut parser = Parser::new();
+        let level = parser.add_kv_arg(KVArg::<u8>::range("level".to_string(), Some('l'), "level".to_string(), 0..=10));
+        assert_eq!(parser.parse_iter(vec!["prog", "-l", "11"]), Err(ParseError::OutOfRange{
+            name: "level".to_string(),
+            value: "11".to_string(),
+            min: "0".to_string(),
+            max: "10".to_string(),
+        }));
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "-l", "5"]).unwrap();
+        assert_eq!(parser.get(level), Some(&5));
+
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<f64>::range("ratio".to_string(), None, "ratio".to_string(), 0.0..=1.0));
+        assert!(matches!(parser.parse_iter(vec!["prog", "--ratio", "1.5"]), Err(ParseError::OutOfRange{..})));
+    }
+
+    #[test]
+    fn collect_all_errors() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::new("count".to_string(), Some('c'), "count".to_string()));
+        let mut name = KVArg::<String>::new("name".to_string(), None, "name".to_string());
+        name.set_required(true);
+        parser.add_kv_arg(name);
+        parser.collect_all_errors(true);
+
+        let result = parser.parse_iter(vec!["prog", "--bogus", "-c", "abc"]);
+        let errors = match result {
+            Err(ParseError::Multiple(errors)) => errors,
+            other => panic!("expected Multiple, got {:?}", other),
+        };
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(errors[0], ParseError::UnknownKey{..}));
+        assert!(matches!(errors[1], ParseError::InvalidValue{..}));
+        assert_eq!(errors[2], ParseError::MissingRequired(vec!["name".to_string()]));
+        assert_eq!(parser.error_index(), Some(1));
+
+        // Just the one, unwrapped
+        parser.reset();
+        assert!(matches!(parser.parse_iter(vec!["prog", "-c", "1"]), Err(ParseError::MissingRequired(_))));
+
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "--bogus", "--help"]), Err(ParseError::HelpRequested));
+    }
+
+    #[test]
+    fn help_verbose() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::builder("format")
+            .desc("output format")
+            .long_desc("One of json or text.\nExample: --format json")
+            .build());
+        parser.add_flag_arg(FlagArg::new("quiet".to_string(), "say less".to_string(), Some('q')));
+
+        let help = parser.help();
+        assert!(help.contains("--format <FORMAT>    output format\n"));
+        assert!(!help.contains("Example"));
+
+        let verbose = parser.help_verbose();
+        assert!(verbose.contains("--format <FORMAT>    output format\n"));
+        assert!(verbose.contains(&format!("\n    {:17}    One of json or text.\n", "")));
+        assert!(verbose.contains("Example: --format json\n"));
+        assert!(verbose.contains("say less\n"));
+    }
+
+    #[test]
+    fn unseparated_value() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::new("output".to_string(), Some('o'), "output file".to_string()));
+        parser.add_kv_arg(KVArg::<String>::new("out".to_string(), None, "other output".to_string()));
+
+        let result = parser.parse_iter(vec!["prog", "--outputfile"]);
+        assert_eq!(result, Err(ParseError::UnseparatedValue{key: "outputfile".to_string(), kv_key: "output".to_string()}));
+        assert_eq!(result.unwrap_err().to_string(),
+            "unknown option `--outputfile`; `--output` takes a value, did you mean `--output file`?");
+
+        // A close match beats the kv prefix
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::new("out".to_string(), None, "output file".to_string()));
+        parser.add_flag_arg(FlagArg::new("outline".to_string(), "outline only".to_string(), None));
+        let result = parser.parse_iter(vec!["prog", "--outlin"]);
+        assert_eq!(result, Err(ParseError::UnknownKey{key: "outlin".to_string(), suggestion: Some("outline".to_string())}));
+
+        let mut parser = Parser::new();
+        parser.case_insensitive(true);
+        parser.add_kv_arg(KVArg::<String>::new("out".to_string(), None, "output file".to_string()));
+        let result = parser.parse_iter(vec!["prog", "--OUTfile"]);
+        assert_eq!(result, Err(ParseError::UnseparatedValue{key: "OUTfile".to_string(), kv_key: "out".to_string()}));
+        let result = parser.parse_iter(vec!["prog", "--OUTT"]);
+        assert_eq!(result, Err(ParseError::UnknownKey{key: "OUTT".to_string(), suggestion: Some("out".to_string())}));
+    }
+
+    #[test]
+    fn optional_value_flag() {
+        let mut parser = Parser::new();
+        let color = parser.add_flag_arg(OptionalValueFlag::new("color".to_string(), "colorize".to_string(), None));
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input".to_string()));
+        assert!(parser.help().contains("--color[=<COLOR>]    colorize\n"));
+
+        // Bare, and the next token is left for the positional
+        parser.parse_iter(vec!["prog", "--color", "always"]).unwrap();
+        assert!(parser.arg(color).found());
+        assert_eq!(parser.arg(color).value(), None);
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--color=always", "in"]).unwrap();
+        assert!(parser.arg(color).found());
+        assert_eq!(parser.arg(color).value(), Some("always"));
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "in"]).unwrap();
+        assert!(!parser.arg(color).found());
+    }
+
+    #[test]
+    fn into_matches() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<i32>::builder("count").short('c').override_mode(true).build());
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let mut build = Parser::new();
+        build.add_flag_arg(FlagArg::new("release".to_string(), "optimize".to_string(), None));
+        parser.add_subcommand("build", build);
+        parser.parse_iter(vec!["prog", "-c", "3", "-c", "4", "build", "--release"]).unwrap();
+
+        let matches = std::sync::Arc::new(parser.into_matches());
+        let shared = std::sync::Arc::clone(&matches);
+        let count = std::thread::spawn(move || shared.value_of::<i32>("count")).join().unwrap();
+        assert_eq!(count, Some(4));
+
+        assert_eq!(matches.values_of("count"), &["3".to_string(), "4".to_string()]);
+        assert!(!matches.is_present("verbose"));
+        let (name, sub) = matches.subcommand().unwrap();
+        assert_eq!(name, "build");
+        assert!(sub.is_present("release"));
+    }
+
+    #[test]
+    fn ignore_unknown() {
+        let mut parser = Parser::new();
+        let known = parser.add_kv_arg(KVArg::<String>::new("known".to_string(), None, "known".to_string()));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        parser.ignore_unknown(true);
+
+        parser.parse_iter(vec!["prog", "--known", "x", "--bogus", "y", "--other=z", "--lone", "-v"]).unwrap();
+        assert_eq!(parser.get(known), Some(&"x".to_string()));
+        assert_eq!(parser.unknown_args(), &["--bogus", "y", "--other=z", "--lone"]);
+        assert!(parser.is_present("verbose"));
+
+        parser.ignore_unknown(false);
+        parser.reset();
+        assert!(matches!(parser.parse_iter(vec!["prog", "--bogus"]), Err(ParseError::UnknownKey{..})));
+    }
+
+    #[test]
+    fn short_value_forms() {
+        let forms: Vec<Vec<&str>> = vec![
+            vec!["prog", "-n=5"],
+            vec!["prog", "-n5"],
+            vec!["prog", "-n", "5"],
+            vec!["prog", "-vn=5"],
+            vec!["prog", "-vn5"],
+            vec!["prog", "-vn", "5"],
+        ];
+        for argv in forms {
+            let mut parser = Parser::new();
+            let n = parser.add_kv_arg(KVArg::<i32>::new("num".to_string(), Some('n'), "num".to_string()));
+            parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+            parser.parse_iter(argv.clone()).unwrap();
+            assert_eq!(parser.get(n), Some(&5), "{:?}", argv);
+        }
+
+        // Only an `=` right after the key is a separator
+        let mut parser = Parser::new();
+        let s = parser.add_kv_arg(KVArg::<String>::new("set".to_string(), Some('s'), "set".to_string()));
+        parser.parse_iter(vec!["prog", "-sa=b"]).unwrap();
+        assert_eq!(parser.get(s), Some(&"a=b".to_string()));
+    }
+
+    #[test]
+    fn describe() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input file".to_string()));
+        let mut level = KVArg::<u32>::new("level".to_string(), Some('l'), "compression \"level\"".to_string());
+        level.set_shown_default(6);
+        parser.add_kv_arg(level);
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), None));
+
+        let specs = parser.describe();
+        assert_eq!(specs.len(), 3);
+        assert_eq!(specs[1], ArgSpec{
+            name: "level".to_string(),
+            short_key: Some('l'),
+            kind: ArgKind::KV,
+            default: Some("6".to_string()),
+            required: false,
+            desc: "compression \"level\"".to_string(),
+        });
+
+        let json = parser.to_json();
+        assert!(json.starts_with('[') && json.ends_with(']'));
+        assert!(json.contains(concat!(r#"{"name":"level","short":"l","kind":"kv","default":"6","required":false,"#,
+            r#""desc":"compression \"level\""}"#)));
+        assert!(json.contains(r#"{"name":"input","short":null,"kind":"pos""#));
+    }
+
+    #[test]
+    fn leftover() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let tool = parser.add_pos_arg(PosArg::<String>::new("tool".to_string(), "tool".to_string()));
+        assert_eq!(parser.parse_iter(vec!["prog", "-v", "cc", "main.c"]), Err(ParseError::TooManyPositional));
+
+        parser.reset();
+        parser.allow_leftover(true);
+        parser.parse_iter(vec!["prog", "-v", "cc", "main.c", "-O2"]).unwrap();
+        assert!(parser.is_present("verbose"));
+        assert_eq!(parser.arg(tool).get(), Some(&"cc".to_string()));
+        assert_eq!(parser.consumed(), 2);
+        assert_eq!(parser.leftover(), &[OsString::from("main.c"), OsString::from("-O2")]);
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "cc"]).unwrap();
+        assert_eq!(parser.consumed(), 1);
+        assert!(parser.leftover().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "`-x` for `extract` is already taken by `exclude`")]
+    fn short_key_collision() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::new("exclude".to_string(), Some('x'), "exclude".to_string()));
+        parser.add_flag_arg(FlagArg::new("extract".to_string(), "extract".to_string(), Some('x')));
+    }
+
+    #[test]
+    fn help_shows_default() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<f64>::builder("ratio").desc("scale").shown_default(1.5).build());
+        parser.add_kv_arg(KVArg::<String>::builder("name").desc("who").shown_default("world".to_string()).build());
+        parser.add_kv_arg(KVArg::<i32>::builder("hidden-default").desc("plain").default(3).build());
+
+        let help = parser.help();
+        assert!(help.contains("scale [default: 1.5]\n"));
+        assert!(help.contains("who [default: world]\n"));
+        assert!(help.contains("plain\n"));
+    }
+
+    #[test]
+    fn separator_in_subcommand() {
+        let mut parser = Parser::new();
+        let x = parser.add_global_flag(FlagArg::new("extra".to_string(), "extra".to_string(), Some('x')));
+        let mut sub = Parser::new();
+        sub.add_pos_arg(PosArg::<String>::new("value".to_string(), "value".to_string()));
+        parser.add_subcommand("sub", sub);
+
+        parser.parse_iter(vec!["prog", "sub", "--", "-x"]).unwrap();
+        assert!(!parser.arg(x).found());
+        let sub = parser.subcommand("sub").unwrap();
+        assert_eq!(sub.value_of::<String>("value"), Some("-x".to_string()));
+        assert!(parser.trailing().is_empty());
+
+        // Before the separator, the global flag is still the parent's
+        parser.reset();
+        parser.parse_iter(vec!["prog", "sub", "-x", "--", "-x"]).unwrap();
+        assert!(parser.arg(x).found());
+        assert_eq!(parser.subcommand("sub").unwrap().value_of::<String>("value"), Some("-x".to_string()));
+    }
+
+    #[test]
+    fn try_value_of() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::new("level".to_string(), None, "level".to_string()));
+        parser.add_kv_arg(KVArg::<String>::new("name".to_string(), None, "name".to_string()));
+        parser.parse_iter(vec!["prog", "--level", "3", "--name", "x"]).unwrap();
+
+        assert_eq!(parser.try_value_of::<u8>("level"), Ok(Some(3)));
+        assert_eq!(parser.try_value_of::<u8>("missing"), Ok(None));
+        assert!(matches!(parser.try_value_of::<u8>("name"), Err(ParseError::InvalidValue{..})));
+        assert_eq!(parser.value_of::<u8>("name"), None);
+    }
+
+    #[test]
+    fn prefix_chars() {
+        let mut parser = Parser::new();
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let out = parser.add_kv_arg(KVArg::<String>::new("out".to_string(), Some('o'), "output".to_string()));
+        let input = parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "input".to_string()));
+        parser.prefix_chars("/");
+
+        parser.parse_iter(vec!["prog", "//verbose", "/o", "x.txt", "-5"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(out), Some(&"x.txt".to_string()));
+        assert_eq!(parser.arg(input).get(), Some(&"-5".to_string()));
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "/v", "//", "/in"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.arg(input).get(), Some(&"/in".to_string()));
+
+        parser.prefix_chars("-/");
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--verbose", "/out=y", "in"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(out), Some(&"y".to_string()));
+    }
+
+    #[test]
+    fn deprecated() {
+        let mut parser = Parser::new();
+        let mut old = FlagArg::new("colour".to_string(), "colorize".to_string(), Some('c'));
+        old.set_deprecated("use --color instead");
+        let old = parser.add_flag_arg(old);
+        parser.add_kv_arg(KVArg::<i32>::builder("jobs").short('j').deprecated("use --threads").build());
+
+        parser.parse_iter(vec!["prog", "--colour", "-j4"]).unwrap();
+        assert!(parser.arg(old).found());
+        assert_eq!(parser.warnings(), &[
+            "`--colour` is deprecated: use --color instead".to_string(),
+            "`-j` is deprecated: use --threads".to_string(),
+        ]);
+
+        parser.reset();
+        parser.parse_iter(vec!["prog"]).unwrap();
+        assert!(parser.warnings().is_empty());
+    }
+
+    #[test]
+    fn negative_number_after_kv() {
+        let mut parser = Parser::new();
+        let offset = parser.add_kv_arg(KVArg::<i32>::new("offset".to_string(), Some('o'), "offset".to_string()));
+        let delta = parser.add_pos_arg(PosArg::<i32>::new("delta".to_string(), "delta".to_string()));
+        parser.allow_negative_numbers(true);
+
+        parser.parse_iter(vec!["prog", "--offset", "3", "-5"]).unwrap();
+        assert_eq!(parser.get(offset), Some(&3));
+        assert_eq!(parser.arg(delta).get(), Some(&-5));
+
+        // And as the kv value itself
+        parser.reset();
+        parser.parse_iter(vec!["prog", "-o", "-3", "-5"]).unwrap();
+        assert_eq!(parser.get(offset), Some(&-3));
+        assert_eq!(parser.arg(delta).get(), Some(&-5));
+    }
+
+    #[test]
+    fn response_files() {
+        let path = std::env::temp_dir().join(format!("args-response-{}.txt", std::process::id()));
+        std::fs::write(&path, "-f 42\n@nested\n").unwrap();
+        let at_path = format!("@{}", path.display());
+
+        let mut parser = Parser::new();
+        let first = parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first".to_string()));
+        let rest = parser.add_pos_arg(VarPosArg::<String>::new("rest".to_string(), "rest".to_string()));
+
+        // Off by default
+        parser.parse_iter(vec!["prog", at_path.as_str()]).unwrap();
+        assert_eq!(parser.arg(rest).get(), std::slice::from_ref(&at_path));
+
+        parser.reset();
+        parser.response_files(true);
+        parser.parse_iter(vec!["prog", at_path.as_str(), "--", "@literal"]).unwrap();
+        assert_eq!(parser.get(first), Some(&42));
+        assert_eq!(parser.arg(rest).get(), &["@nested".to_string(), "@literal".to_string()]);
+
+        parser.reset();
+        assert!(matches!(parser.parse_iter(vec!["prog", "x", "@/nonexistent/args"]), Err(ParseError::ResponseFile{..})));
+        assert_eq!(parser.error_index(), Some(2));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn help_groups() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::new("input".to_string(), Some('i'), "read from".to_string()));
+        parser.add_kv_arg(KVArg::<String>::new("output".to_string(), Some('o'), "write to".to_string()));
+        parser.add_kv_arg(KVArg::<String>::new("format".to_string(), None, "output format".to_string()));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let input = parser.add_group("Input options");
+        let output = parser.add_group("Output options");
+        parser.add_to_group(input, "input");
+        parser.add_to_group(output, "output");
+        parser.add_to_group(output, "format");
+
+        let help = parser.help();
+        let options = help.find("\noptions:\n").unwrap();
+        let input_at = help.find("\nInput options:\n").unwrap();
+        let output_at = help.find("\nOutput options:\n").unwrap();
+        assert!(options < input_at && input_at < output_at);
+
+        assert!(help[options..input_at].contains("--verbose"));
+        assert!(help[options..input_at].contains("--help"));
+        assert!(help[input_at..output_at].contains("--input"));
+        assert!(!help[input_at..output_at].contains("--output"));
+        assert!(help[output_at..].contains("--format"));
+        assert!(help[output_at..].contains("--output"));
+    }
+
+    #[test]
+    fn kv_raw() {
+        let mut parser = Parser::new();
+        let count = parser.add_kv_arg(KVArg::<i32>::with_default("count".to_string(), None, "count".to_string(), 1));
+        parser.parse_iter(vec!["prog"]).unwrap();
+        assert_eq!(parser.arg(count).raw(), None);
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--count", "042"]).unwrap();
+        assert_eq!(parser.arg(count).raw(), Some("042"));
+        assert_eq!(parser.arg_mut(count).val(), Some(42));
+    }
+
+    #[test]
+    fn required_unless_any() {
+        let mut parser = Parser::new();
+        parser.add_kv_arg(KVArg::<String>::new("config".to_string(), None, "config file".to_string()));
+        parser.add_kv_arg(KVArg::<String>::new("config-dir".to_string(), None, "config directory".to_string()));
+        parser.add_flag_arg(FlagArg::new("defaults".to_string(), "use defaults".to_string(), None));
+        parser.required_unless_any("config", &["config-dir", "defaults"]);
+
+        assert_eq!(parser.parse_iter(vec!["prog"]), Err(ParseError::MissingRequired(vec!["config".to_string()])));
+
+        for argv in [vec!["prog", "--config", "a"], vec!["prog", "--config-dir", "d"], vec!["prog", "--defaults"]] {
+            parser.reset();
+            parser.parse_iter(argv).unwrap();
+        }
+    }
+
+    #[test]
+    fn empty_token() {
+        let mut parser = Parser::new();
+        let name = parser.add_pos_arg(PosArg::<String>::new("name".to_string(), "name".to_string()));
+        let sep = parser.add_kv_arg(KVArg::<String>::new("sep".to_string(), None, "separator".to_string()));
+        parser.parse_iter(vec!["prog", "", "--sep", ""]).unwrap();
+        assert_eq!(parser.arg(name).get(), Some(&String::new()));
+        assert_eq!(parser.get(sep), Some(&String::new()));
+
+        // Even with other prefix chars
+        parser.reset();
+        parser.prefix_chars("/");
+        parser.parse_iter(vec!["prog", ""]).unwrap();
+        assert_eq!(parser.arg(name).get(), Some(&String::new()));
+    }
+
+    #[test]
+    fn parse_str() {
+        let mut parser = Parser::new();
+        let first = parser.add_kv_arg(KVArg::<i32>::new("first".to_string(), Some('f'), "first".to_string()));
+        parser.parse_str("prog --first 42").unwrap();
+        assert_eq!(parser.get(first), Some(&42));
+
+        assert_eq!(shell_split(r#"prog  'a b' "c \"d\"" e\ f '' x'y'z"#),
+            vec!["prog", "a b", "c \"d\"", "e f", "", "xyz"]);
+    }
+
+    #[test]
+    fn collect_extras() {
+        let mut parser = Parser::new();
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        assert_eq!(parser.parse_iter(vec!["prog", "a"]), Err(ParseError::TooManyPositional));
+
+        parser.reset();
+        parser.collect_extras(true);
+        parser.parse_iter(vec!["prog", "a.txt", "b.txt", "-v", "c.txt", "--", "d.txt"]).unwrap();
+        assert_eq!(parser.extras(), &["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(parser.trailing(), &["d.txt"]);
+        assert!(parser.arg(verbose).found());
+    }
+
+    #[test]
+    fn bundle_tail_value() {
+        let mut parser = Parser::new();
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        let out = parser.add_kv_arg(KVArg::<String>::new("output".to_string(), Some('o'), "output".to_string()));
+        parser.parse_iter(vec!["prog", "-vo", "out.txt"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.get(out), Some(&"out.txt".to_string()));
+
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "-vo"]), Err(ParseError::MissingValue("o".to_string())));
+    }
+
+    #[test]
+    fn subcommand_kind() {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        enum Cmd { Build, Test }
+        impl SubcommandKind for Cmd {
+            fn name(&self) -> &str {
+                match self {
+                    Cmd::Build => "build",
+                    Cmd::Test => "test",
+                }
+            }
+            fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    "build" => Some(Cmd::Build),
+                    "test" => Some(Cmd::Test),
+                    _ => None,
+                }
+            }
+        }
+
+        let mut parser = Parser::new();
+        parser.add_subcommand_kind(Cmd::Build, Parser::new());
+        parser.add_subcommand_kind(Cmd::Test, Parser::new());
+        assert_eq!(parser.subcommand_kind::<Cmd>(), None);
+
+        parser.parse_iter(vec!["prog", "test"]).unwrap();
+        assert_eq!(parser.subcommand_kind::<Cmd>(), Some(Cmd::Test));
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "build"]).unwrap();
+        assert_eq!(parser.subcommand_kind::<Cmd>(), Some(Cmd::Build));
+    }
+
+    #[test]
+    fn fixed_arity() {
+        let mut parser = Parser::new();
+        let point = parser.add_kv_arg(FixedArityArg::<i32>::new("point".to_string(), Some('p'), "x y".to_string(), 2));
+        parser.add_flag_arg(FlagArg::new("verbose".to_string(), "be loud".to_string(), Some('v')));
+        parser.parse_iter(vec!["prog", "--point", "3", "4"]).unwrap();
+        assert_eq!(parser.arg(point).get(), &[3, 4]);
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "-vp=-1", "2"]).unwrap();
+        assert_eq!(parser.arg_mut(point).vals(), vec![-1, 2]);
+
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "--point", "3"]), Err(ParseError::MissingValue("point".to_string())));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "--point", "3", "-v"]), Err(ParseError::MissingValue("point".to_string())));
+    }
+
+    #[test]
+    fn value_style() {
+        let mut parser = Parser::new();
+        let jobs = parser.add_kv_arg(KVArg::<u32>::builder("jobs").short('j').value_style(ValueStyle::Equals).build());
+        let out = parser.add_kv_arg(KVArg::<String>::builder("out").short('o').value_style(ValueStyle::Space).build());
+        let either = parser.add_kv_arg(KVArg::<String>::new("either".to_string(), Some('e'), "".to_string()));
+
+        for args in [vec!["prog", "--jobs=4"], vec!["prog", "-j4"], vec!["prog", "-j=4"]] {
+            parser.reset();
+            parser.parse_iter(args).unwrap();
+            assert_eq!(parser.get(jobs), Some(&4));
+        }
+        for args in [vec!["prog", "--jobs", "4"], vec!["prog", "-j", "4"]] {
+            parser.reset();
+            let key = args[1].trim_start_matches('-').to_string();
+            assert_eq!(parser.parse_iter(args), Err(ParseError::WrongValueStyle{key, style: ValueStyle::Equals}));
+        }
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--out", "a", "--either=b"]).unwrap();
+        assert_eq!(parser.get(out).map(|s| &s[..]), Some("a"));
+        assert_eq!(parser.get(either).map(|s| &s[..]), Some("b"));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "--out=a"]),
+            Err(ParseError::WrongValueStyle{key: "out".to_string(), style: ValueStyle::Space}));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "-oa"]),
+            Err(ParseError::WrongValueStyle{key: "o".to_string(), style: ValueStyle::Space}));
+
+        assert_eq!(ParseError::WrongValueStyle{key: "jobs".to_string(), style: ValueStyle::Equals}.to_string(),
+            "`--jobs` takes its value in the same token, e.g. `--jobs=VALUE`");
+    }
+
+    #[test]
+    fn error_with_usage() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "".to_string()));
+        assert_eq!(parser.usage(), "usage: [options] <input>\n");
+
+        let err = parser.parse_iter(vec!["prog", "--bogus"]).unwrap_err();
+        let out = parser.render_error_with_usage(&err, false);
+        assert_eq!(out, "error: unknown option `--bogus`\nusage: [options] <input>\n");
+
+        assert!(parser.help().contains(&parser.usage()));
+    }
+
+    #[test]
+    fn tri_state_flag() {
+        let mut parser = Parser::new();
+        let feature = parser.add_flag_arg(TriStateFlag::new("feature".to_string(), "".to_string(), None));
+
+        parser.parse_iter(vec!["prog"]).unwrap();
+        assert_eq!(parser.arg(feature).get(), None);
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--feature"]).unwrap();
+        assert_eq!(parser.arg(feature).get(), Some(true));
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--no-feature"]).unwrap();
+        assert_eq!(parser.arg(feature).get(), Some(false));
+
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "--feature", "--no-feature"]),
+            Err(ParseError::DuplicateArg("no-feature".to_string())));
+    }
+
+    #[test]
+    #[should_panic(expected = "subcommand `build` is already registered")]
+    fn subcommand_collision() {
+        let mut parser = Parser::new();
+        parser.add_subcommand("build", Parser::new());
+        parser.add_subcommand("build", Parser::new());
+    }
+
+    #[test]
+    fn subcommand_named_like_positional() {
+        let mut parser = Parser::new();
+        parser.add_pos_arg(PosArg::<String>::new("build".to_string(), "".to_string()));
+        parser.add_subcommand("test", Parser::new());
+        assert!(parser.warnings().is_empty());
+        parser.add_subcommand("build", Parser::new());
+        assert_eq!(parser.warnings(), &["subcommand `build` has the same name as positional `<build>`".to_string()]);
+    }
+
+    #[test]
+    fn positional_named_like_subcommand() {
+        let mut parser = Parser::new();
+        parser.add_subcommand("build", Parser::new());
+        parser.add_pos_arg(PosArg::<String>::new("test".to_string(), "".to_string()));
+        assert!(parser.warnings().is_empty());
+        parser.add_pos_arg(PosArg::<String>::new("build".to_string(), "".to_string()));
+        assert_eq!(parser.warnings(), &["positional `<build>` has the same name as subcommand `build`".to_string()]);
+    }
+
+    #[test]
+    fn command_positional() {
+        let mut parser = Parser::new();
+        let command = parser.command_positional(&["up", "down"]);
+        let steps = parser.add_pos_arg(PosArg::<u32>::new("steps".to_string(), "".to_string()));
+
+        parser.parse_iter(vec!["prog", "down", "3"]).unwrap();
+        assert_eq!(parser.arg(command).get(), Some("down"));
+        assert_eq!(parser.arg(steps).get(), Some(&3));
+
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "sideways"]), Err(ParseError::InvalidChoice{
+            name: "command".to_string(),
+            value: "sideways".to_string(),
+            allowed: vec!["up".to_string(), "down".to_string()],
+        }));
+        parser.reset();
+        assert!(parser.parse_iter(vec!["prog"]).is_err());
+        assert!(parser.help().contains("usage: [options] <command> <steps>\n"));
+        assert!(parser.help().lines().any(|line| line.trim() == "<command>     [possible values: up, down]"));
+    }
+
+    #[test]
+    fn parse_partial() {
+        let mut parser = Parser::new();
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose".to_string(), "".to_string(), Some('v')));
+        let plugin = parser.add_pos_arg(PosArg::<String>::new("plugin".to_string(), "".to_string()));
+
+        let rest = parser.parse_partial(vec!["prog", "-v", "resize", "--width=3", "in.png", "--", "-x"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(rest, vec!["--width=3", "in.png", "--", "-x"]);
+        assert_eq!(parser.arg(plugin).get().map(|s| &s[..]), Some("resize"));
+
+        // Second round, with the plugin's own args
+        let mut plugin_parser = Parser::new();
+        let width = plugin_parser.add_kv_arg(KVArg::<u32>::new("width".to_string(), None, "".to_string()));
+        let input = plugin_parser.add_pos_arg(PosArg::<String>::new("input".to_string(), "".to_string()));
+        plugin_parser.parse_iter(std::iter::once(String::from("prog")).chain(rest)).unwrap();
+        assert_eq!(plugin_parser.get(width), Some(&3));
+        assert_eq!(plugin_parser.arg(input).get().map(|s| &s[..]), Some("in.png"));
+        assert_eq!(plugin_parser.trailing(), &["-x".to_string()]);
+
+        // The settings parse_partial() needs don't stick
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "resize", "in.png"]), Err(ParseError::TooManyPositional));
+    }
+
+    #[test]
+    fn max_occurs() {
+        let mut parser = Parser::new();
+        let mut include = MultiKVArg::<String>::new("include".to_string(), Some('I'), "".to_string());
+        include.set_max_occurs(2);
+        let include = parser.add_kv_arg(include);
+        let mut verbose = CountArg::new("verbose".to_string(), "".to_string(), Some('v'));
+        verbose.set_max_occurs(2);
+        let verbose = parser.add_count_arg(verbose);
+
+        parser.parse_iter(vec!["prog", "-I", "a", "--include=b", "-vv"]).unwrap();
+        assert_eq!(parser.arg_mut(include).vals(), vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(parser.arg(verbose).count(), 2);
+
+        let too_many = |name: &str| Err(ParseError::TooManyOccurrences{name: name.to_string(), max: 2});
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "-Ia", "-Ib", "-Ic"]), too_many("include"));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "-v", "-vv"]), too_many("verbose"));
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "-v", "--verbose", "--verbose"]), too_many("verbose"));
+    }
+
+    #[test]
+    fn help_value_placeholders() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose", "be loud", Some('v')));
+        parser.add_kv_arg(KVArg::<String>::builder("output").short('o').value_name("FILE").required(true).build());
+        parser.add_kv_arg(KVArg::<u32>::new("jobs", Some('j'), "parallelism"));
+        parser.add_pos_arg(PosArg::<String>::new("input", ""));
+
+        let help = parser.help();
+        assert!(help.starts_with("usage: [options] --output <FILE> <input>\n"));
+        let rows: Vec<String> = help.lines().map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")).collect();
+        assert!(rows.contains(&"--verbose, -v be loud".to_string()));
+        assert!(rows.contains(&"--output, -o <FILE>".to_string()));
+        assert!(rows.contains(&"--jobs, -j <JOBS> parallelism".to_string()));
+    }
+
+    #[test]
+    fn parse_into() {
+        #[derive(Debug, Default, PartialEq)]
+        struct Config {
+            input: String,
+            jobs: u32,
+            verbose: bool,
+            name: Option<String>,
+        }
+
+        let mut parser = Parser::new();
+        parser.add_pos_arg_into(PosArg::<String>::new("input", ""), |val, config: &mut Config| config.input = val);
+        parser.add_kv_arg_into(KVArg::with_default("jobs", Some('j'), "", 1), |val, config: &mut Config| config.jobs = val);
+        parser.add_kv_arg_into(KVArg::<String>::new("name", None, ""), |val, config: &mut Config| config.name = Some(val));
+        parser.add_flag_arg_into(FlagArg::new("verbose", "", Some('v')), |val, config: &mut Config| config.verbose = val);
+
+        parser.parse_iter(vec!["prog", "-v", "in.txt"]).unwrap();
+        let mut config = Config{name: Some("untouched".to_string()), ..Config::default()};
+        parser.parse_into(&mut config);
+        assert_eq!(config, Config{input: "in.txt".to_string(), jobs: 1, verbose: true, name: Some("untouched".to_string())});
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--jobs", "4", "--name", "x", "in.txt"]).unwrap();
+        let mut config = Config::default();
+        parser.parse_into(&mut config);
+        assert_eq!(config, Config{input: "in.txt".to_string(), jobs: 4, verbose: false, name: Some("x".to_string())});
+    }
+
+    #[test]
+    fn flag_takes_no_value() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose", "", Some('v')));
+        parser.add_flag_arg(NegatableFlag::new("color".to_string(), "".to_string(), None));
+        parser.add_count_arg(CountArg::new("debug".to_string(), "".to_string(), Some('d')));
+
+        for (arg, key) in [("--verbose=true", "verbose"), ("--no-color=1", "no-color"), ("--debug=2", "debug")] {
+            parser.reset();
+            assert_eq!(parser.parse_iter(vec!["prog", arg]), Err(ParseError::FlagTakesNoValue(key.to_string())));
+        }
+        assert_eq!(ParseError::FlagTakesNoValue("verbose".to_string()).to_string(), "`--verbose` takes no value");
+
+        // Anything else before the `=` is still unknown
+        parser.reset();
+        assert_eq!(parser.parse_iter(vec!["prog", "--verbosity=2"]),
+            Err(ParseError::UnknownKey{key: "verbosity=2".to_string(), suggestion: None}));
+    }
+
+    #[test]
+    fn default_subcommand() {
+        let mut run = Parser::new();
+        run.add_pos_arg(PosArg::<String>::new("script", ""));
+        run.add_flag_arg(FlagArg::new("watch", "", Some('w')));
+        let mut parser = Parser::new();
+        let verbose = parser.add_flag_arg(FlagArg::new("verbose", "", Some('v')));
+        parser.add_subcommand("run", run);
+        parser.add_subcommand("init", Parser::new());
+        parser.default_subcommand("run");
+
+        parser.parse_iter(vec!["prog", "-v", "main.js", "-w"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        assert_eq!(parser.matched_subcommand(), Some("run"));
+        let run = parser.subcommand("run").unwrap();
+        assert_eq!(run.value_of::<String>("script"), Some("main.js".to_string()));
+        assert!(run.is_present("watch"));
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "init"]).unwrap();
+        assert_eq!(parser.matched_subcommand(), Some("init"));
+        assert!(!parser.subcommand("run").unwrap().is_present("script"));
+
+        // With nothing at all, the default still runs
+        parser.reset();
+        parser.parse_iter(vec!["prog"]).unwrap();
+        assert_eq!(parser.matched_subcommand(), Some("run"));
+
+        // Everything after `--` goes to the default, still as positionals
+        parser.reset();
+        parser.parse_iter(vec!["prog", "-v", "--", "-w"]).unwrap();
+        assert!(parser.arg(verbose).found());
+        let run = parser.subcommand("run").unwrap();
+        assert_eq!(run.value_of::<String>("script"), Some("-w".to_string()));
+        assert!(!run.is_present("watch"));
+
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--", "init"]).unwrap();
+        assert_eq!(parser.matched_subcommand(), Some("run"));
+        assert_eq!(parser.subcommand("run").unwrap().value_of::<String>("script"), Some("init".to_string()));
+    }
+
+    #[test]
+    fn default_subcommand_error_index() {
+        let mut run = Parser::new();
+        let mut script = PosArg::<String>::new("script", "");
+        script.set_required(true);
+        run.add_pos_arg(script);
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose", "", Some('v')));
+        parser.add_kv_arg(KVArg::<i32>::new("num", Some('n'), ""));
+        parser.add_subcommand("run", run);
+        parser.default_subcommand("run");
+
+        // Nothing was given for it, so there's no token to blame
+        let err = parser.parse_located(vec!["prog"]).unwrap_err();
+        assert_eq!(err.kind, ParseError::MissingPositional("script".to_string()));
+        assert_eq!(err.index, Some(1));
+
+        parser.reset();
+        let err = parser.parse_located(vec!["prog", "-v"]).unwrap_err();
+        assert_eq!(err.kind, ParseError::MissingPositional("script".to_string()));
+        assert_eq!(err.index, Some(2));
+
+        // A nested default points past the end too
+        let mut inner = Parser::new();
+        let mut file = PosArg::<String>::new("file", "");
+        file.set_required(true);
+        inner.add_pos_arg(file);
+        let mut run = Parser::new();
+        run.add_subcommand("inner", inner);
+        run.default_subcommand("inner");
+        let mut nested = Parser::new();
+        nested.add_subcommand("run", run);
+        let err = nested.parse_located(vec!["prog", "run"]).unwrap_err();
+        assert_eq!(err.kind, ParseError::MissingPositional("file".to_string()));
+        assert_eq!(err.index, Some(2));
+
+        // Past the end of argv as given, not as expanded
+        let path = std::env::temp_dir().join(format!("args-default-sub-{}.txt", std::process::id()));
+        std::fs::write(&path, "-n 1").unwrap();
+        let at_path = format!("@{}", path.display());
+        parser.reset();
+        parser.response_files(true);
+        let err = parser.parse_located(vec!["prog", at_path.as_str()]).unwrap_err();
+        assert_eq!(err.kind, ParseError::MissingPositional("script".to_string()));
+        assert_eq!(err.index, Some(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn iter_results() {
+        let mut parser = Parser::new();
+        parser.add_flag_arg(FlagArg::new("verbose", "", Some('v')));
+        parser.add_kv_arg(KVArg::<u32>::new("jobs", Some('j'), ""));
+        parser.add_kv_arg(KVArg::<String>::new("output", Some('o'), ""));
+        parser.add_pos_arg(PosArg::<String>::new("input", ""));
+        parser.add_count_arg(CountArg::new("debug".to_string(), "".to_string(), Some('d')));
+
+        parser.parse_iter(vec!["prog", "-j", "2", "-dd", "in.txt"]).unwrap();
+        let results: Vec<(&str, bool)> = parser.iter_results().collect();
+        assert_eq!(results, vec![("input", true), ("jobs", true), ("output", false), ("verbose", false), ("debug", true)]);
+    }
+
+    #[test]
+    fn escaped_delimiter() {
+        let mut paths = MultiKVArg::<String>::new("paths".to_string(), None, "".to_string());
+        paths.set_delimiter(',');
         let mut parser = Parser::new();
-        parser.add_kv_arg(&kv);
+        let paths = parser.add_kv_arg(paths);
 
-        let args = vec!["".to_string(), "-f".to_string(), "42".to_string()];
+        parser.parse_iter(vec!["prog", "--paths", "a\\,b,c"]).unwrap();
+        assert_eq!(parser.arg_mut(paths).vals(), vec!["a,b".to_string(), "c".to_string()]);
 
-        parser.parse_vec(args);
+        // Only the delimiter and backslash are escaped
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--paths", "C:\\dir,,d\\"]).unwrap();
+        assert_eq!(parser.arg_mut(paths).vals(), vec!["C:\\dir".to_string(), "".to_string(), "d\\".to_string()]);
 
-        assert!(kv.borrow_mut().val().unwrap() == 42);
+        // So a value can end in a backslash before the delimiter
+        parser.reset();
+        parser.parse_iter(vec!["prog", "--paths", "C:\\dir\\\\,d\\\\\\,e"]).unwrap();
+        assert_eq!(parser.arg_mut(paths).vals(), vec!["C:\\dir\\".to_string(), "d\\,e".to_string()]);
     }
 }